@@ -1,20 +1,197 @@
-use cpal::{Device, OutputCallbackInfo, SampleFormat, StreamConfig};
+use cpal::{BufferSize, BuildStreamError, Device, DevicesError, Host, HostUnavailable, OutputCallbackInfo, Sample, SampleFormat, SampleRate, StreamConfig, StreamError, SupportedBufferSize, SupportedStreamConfigsError};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+type Callback = Box<dyn FnMut(&mut [f32], &OutputCallbackInfo) + Send>;
+type ErrorCallback = Box<dyn FnMut(StreamError) + Send>;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    NoSuchHost(String),
+    HostUnavailable(HostUnavailable),
+    Devices(DevicesError),
+    NoOutputDevice,
+    NoSuchDevice(String),
+    NoSupportedConfig(SupportedStreamConfigsError),
+    NoSupportedFormat,
+    UnsupportedBufferSize(u32),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSuchHost(name) => write!(f, "no host named {name:?}"),
+            Self::HostUnavailable(error) => write!(f, "host unavailable: {error}"),
+            Self::Devices(error) => write!(f, "couldn't list devices: {error}"),
+            Self::NoOutputDevice => write!(f, "missing output device"),
+            Self::NoSuchDevice(name) => write!(f, "no output device named {name:?}"),
+            Self::NoSupportedConfig(error) => write!(f, "no supported output config: {error}"),
+            Self::NoSupportedFormat => write!(f, "no supported sample format"),
+            Self::UnsupportedBufferSize(frames) => write!(f, "unsupported buffer size of {frames} frames"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::HostUnavailable(error) => Some(error),
+            Self::Devices(error) => Some(error),
+            Self::NoSupportedConfig(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+fn log_error(error: StreamError) {
+    eprintln!("stream error: {error}");
+}
+
+// What cpal is handed as the stream's error callback, forwarding to the application's handler.
+fn error_callback(on_error: impl FnMut(StreamError) + Send + 'static) -> ErrorCallback {
+    Box::new(on_error)
+}
 
 pub struct Config {
     device: Device,
     config: StreamConfig,
+    sample_format: SampleFormat,
+}
+
+// Higher is preferred: f32 needs no conversion, and i16 is more common than u16.
+fn format_rank(format: SampleFormat) -> u32 {
+    match format {
+        SampleFormat::F32 => 2,
+        SampleFormat::I16 => 1,
+        SampleFormat::U16 => 0,
+    }
+}
+
+fn find_host(name: &str) -> Result<Host, ConfigError> {
+    let id = cpal::available_hosts().into_iter()
+        .find(|id| id.name() == name)
+        .ok_or_else(|| ConfigError::NoSuchHost(name.to_string()))?;
+    cpal::host_from_id(id).map_err(ConfigError::HostUnavailable)
+}
+
+// Devices that fail to report a name are skipped.
+fn device_names(host: &Host) -> Result<Vec<String>, ConfigError> {
+    let devices = host.output_devices().map_err(ConfigError::Devices)?;
+    Ok(devices.filter_map(|device| device.name().ok()).collect())
+}
+
+fn find_device(host: &Host, name: &str) -> Result<Device, ConfigError> {
+    host.output_devices()
+        .map_err(ConfigError::Devices)?
+        .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+        .ok_or_else(|| ConfigError::NoSuchDevice(name.to_string()))
+}
+
+#[derive(Default)]
+pub struct ConfigBuilder {
+    host: Option<String>,
+    device: Option<String>,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+}
+
+impl ConfigBuilder {
+    pub fn host(mut self, name: &str) -> Self {
+        self.host = Some(name.to_string());
+        self
+    }
+
+    pub fn device(mut self, name: &str) -> Self {
+        self.device = Some(name.to_string());
+        self
+    }
+
+    // Falls back to the nearest supported rate. Without this, the highest rate is used.
+    pub fn sample_rate(mut self, rate: u32) -> Self {
+        self.sample_rate = Some(rate);
+        self
+    }
+
+    // In frames. Without this, the host's default is used, which may have high latency.
+    pub fn buffer_size(mut self, frames: u32) -> Self {
+        self.buffer_size = Some(frames);
+        self
+    }
+
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let host = match &self.host {
+            Some(name) => find_host(name)?,
+            None => cpal::default_host(),
+        };
+        let device = match &self.device {
+            Some(name) => find_device(&host, name)?,
+            None => host.default_output_device().ok_or(ConfigError::NoOutputDevice)?,
+        };
+        let configs: Vec<_> = device.supported_output_configs()
+            .map_err(ConfigError::NoSupportedConfig)?
+            .collect();
+        if configs.is_empty() {
+            return Err(ConfigError::NoSupportedFormat);
+        }
+
+        let (config_range, sample_rate) = configs.into_iter()
+            .filter(|config| match (self.buffer_size, config.buffer_size()) {
+                (Some(frames), SupportedBufferSize::Range { min, max }) => (*min..=*max).contains(&frames),
+                _ => true,
+            })
+            .map(|config| {
+                let min = config.min_sample_rate().0;
+                let max = config.max_sample_rate().0;
+                let rate = self.sample_rate.map_or(max, |rate| rate.clamp(min, max));
+                (config, rate)
+            })
+            // Nearest rate first, then the preferred format.
+            .min_by_key(|(config, rate)| {
+                let distance = self.sample_rate.map_or(0, |requested| requested.abs_diff(*rate));
+                (distance, std::cmp::Reverse(format_rank(config.sample_format())))
+            })
+            .ok_or(ConfigError::UnsupportedBufferSize(self.buffer_size.unwrap_or_default()))?;
+
+        let sample_format = config_range.sample_format();
+        let mut config = config_range.with_sample_rate(SampleRate(sample_rate)).config();
+        if let Some(frames) = self.buffer_size {
+            config.buffer_size = BufferSize::Fixed(frames);
+        }
+        Ok(Config { device, config, sample_format })
+    }
 }
 
 impl Config {
-    pub fn get() -> Self {
-        let host = cpal::default_host();
-        let device = host.default_output_device().expect("missing output device");
-        let config_range = device.supported_output_configs().unwrap()
-            .find(|config| config.sample_format() == SampleFormat::F32)
-            .expect("no f32 format support");
-        let config = config_range.with_max_sample_rate().config();
-        Self { device, config }
+    pub fn get() -> Result<Self, ConfigError> {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    pub fn list_hosts() -> Vec<String> {
+        cpal::available_hosts().iter().map(|id| id.name().to_string()).collect()
+    }
+
+    // Output device names on the default host.
+    pub fn list_devices() -> Vec<String> {
+        device_names(&cpal::default_host()).unwrap_or_default()
+    }
+
+    pub fn list_host_devices(host: &str) -> Result<Vec<String>, ConfigError> {
+        device_names(&find_host(host)?)
+    }
+
+    // Picks an output device on the default host by name.
+    pub fn with_device(name: &str) -> Result<Self, ConfigError> {
+        Self::builder().device(name).build()
+    }
+
+    pub fn with_host_device(host: &str, device: &str) -> Result<Self, ConfigError> {
+        Self::builder().host(host).device(device).build()
     }
 
     pub fn channels(&self) -> u32 {
@@ -25,24 +202,462 @@ impl Config {
         self.config.sample_rate.0
     }
 
-    pub fn create_stream(&self, mut f: impl FnMut(&mut [f32], &OutputCallbackInfo) + Send + 'static) -> Stream {
-        return imp(&self.device, &self.config, Box::new(move |buf, info| f(buf, info)));
+    pub fn sample_format(&self) -> SampleFormat {
+        self.sample_format
+    }
+
+    // Stream errors, such as the device being unplugged, are logged to stderr.
+    pub fn create_stream(&self, f: impl FnMut(&mut [f32], &OutputCallbackInfo) + Send + 'static) -> Stream {
+        self.create_stream_with_error_handler(f, log_error)
+    }
+
+    // on_error is called from the audio thread, so the application can recover or reconnect.
+    pub fn create_stream_with_error_handler(
+        &self,
+        mut f: impl FnMut(&mut [f32], &OutputCallbackInfo) + Send + 'static,
+        on_error: impl FnMut(StreamError) + Send + 'static,
+    ) -> Stream {
+        let shared = Arc::new(Shared::new());
+        let callback_shared = shared.clone();
+        let samples_per_sec = (self.sample_rate() * self.channels()) as f32;
+        let stream = imp(&self.device, &self.config, self.sample_format, Box::new(move |buf, info| {
+            f(buf, info);
+            callback_shared.process(buf, samples_per_sec);
+        }), error_callback(on_error));
+        return Stream { stream, shared, playing: AtomicBool::new(false) };
+
+        fn imp(device: &Device, config: &StreamConfig, sample_format: SampleFormat, f: Callback, on_error: ErrorCallback) -> cpal::Stream {
+            match sample_format {
+                SampleFormat::F32 => device.build_output_stream(
+                    config,
+                    f,
+                    on_error,
+                ),
+                SampleFormat::I16 => build_converted::<i16>(device, config, f, on_error),
+                SampleFormat::U16 => build_converted::<u16>(device, config, f, on_error),
+            }.unwrap()
+        }
+
+        // Renders into an f32 scratch buffer, then clamps and scales into the device format.
+        fn build_converted<T: Sample>(device: &Device, config: &StreamConfig, mut f: Callback, on_error: ErrorCallback) -> Result<cpal::Stream, BuildStreamError> {
+            // Sized up front so the callback doesn't allocate. Hosts can still hand over more
+            // than the configured size, or the default size isn't known until playing, in which
+            // case it grows once on the first larger buffer.
+            let frames = match config.buffer_size {
+                BufferSize::Fixed(frames) => frames as usize,
+                BufferSize::Default => 4096,
+            };
+            let mut scratch = vec![0.0; frames * config.channels as usize];
+            device.build_output_stream(
+                config,
+                move |buf: &mut [T], info: &OutputCallbackInfo| {
+                    if scratch.len() < buf.len() {
+                        scratch.resize(buf.len(), 0.0);
+                    }
+                    let scratch = &mut scratch[..buf.len()];
+                    f(scratch, info);
+                    convert(scratch, buf);
+                },
+                on_error,
+            )
+        }
+    }
+}
+
+fn convert<T: Sample>(samples: &[f32], out: &mut [T]) {
+    for (out, sample) in out.iter_mut().zip(samples) {
+        *out = T::from(&sample.clamp(-1.0, 1.0));
+    }
+}
+
+// f32 stored as bits, since std has no atomic floats.
+struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+// State shared with the stream callback, which must never block on it.
+struct Shared {
+    volume: AtomicF32,
+    level: AtomicF32,
+    // Seconds for the meter to fall by 1/e.
+    meter_release: AtomicF32,
+    observer: OnceLock<Arc<SampleRing>>,
+}
+
+impl Shared {
+    fn new() -> Self {
+        Self {
+            volume: AtomicF32::new(1.0),
+            level: AtomicF32::new(0.0),
+            meter_release: AtomicF32::new(0.3),
+            observer: OnceLock::new(),
+        }
+    }
+
+    // Applied to each buffer the stream callback rendered.
+    fn process(&self, buf: &mut [f32], samples_per_sec: f32) {
+        let volume = self.volume.load();
+        if volume != 1.0 {
+            for sample in buf.iter_mut() {
+                *sample *= volume;
+            }
+        }
+
+        // Peak meter: jumps up to the buffer's peak, and falls back exponentially.
+        let peak = buf.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+        let release = self.meter_release.load();
+        let decay = if release > 0.0 { (-(buf.len() as f32) / (samples_per_sec * release)).exp() } else { 0.0 };
+        self.level.store(peak.max(self.level.load() * decay));
+
+        if let Some(ring) = self.observer.get() {
+            for &sample in buf.iter() {
+                ring.push(sample);
+            }
+        }
+    }
+}
+
+// Bounded single producer, single consumer queue of samples. Pushing never blocks or
+// allocates, so it's safe on the audio thread: when the consumer falls behind, new samples
+// are dropped and counted instead. Only the stream pushes, callers get a SampleConsumer.
+struct SampleRing {
+    slots: Box<[AtomicF32]>,
+    // Total pushed and popped, so the difference is how many are waiting.
+    write: AtomicUsize,
+    read: AtomicUsize,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl SampleRing {
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity.max(1)).map(|_| AtomicF32::new(0.0)).collect(),
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
 
-        fn imp(device: &Device, config: &StreamConfig, f: Box<dyn FnMut(&mut [f32], &OutputCallbackInfo) + Send>) -> Stream {
-            let stream = device.build_output_stream(
-                &config,
-                f,
-                |error| panic!("{error}"),
-            ).unwrap();
-            Stream(stream)
+    // Producer side only. Returns false if the ring was full and the sample was dropped.
+    fn push(&self, sample: f32) -> bool {
+        let write = self.write.load(Ordering::Relaxed);
+        if write.wrapping_sub(self.read.load(Ordering::Acquire)) >= self.slots.len() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
         }
+        self.slots[write % self.slots.len()].store(sample);
+        // Release, so the consumer sees the slot written before the new count.
+        self.write.store(write.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    // Consumer side only, oldest first.
+    fn pop(&self) -> Option<f32> {
+        let read = self.read.load(Ordering::Relaxed);
+        if read == self.write.load(Ordering::Acquire) {
+            return None;
+        }
+        let sample = self.slots[read % self.slots.len()].load();
+        self.read.store(read.wrapping_add(1), Ordering::Release);
+        Some(sample)
+    }
+
+    fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
     }
 }
 
-pub struct Stream(cpal::Stream);
+// The reading end of a stream's sample ring, from Stream::sample_ring.
+pub struct SampleConsumer {
+    ring: Arc<SampleRing>,
+}
+
+impl SampleConsumer {
+    // Oldest first, None when nothing is waiting.
+    pub fn pop(&self) -> Option<f32> {
+        self.ring.pop()
+    }
+
+    // Samples lost to a full ring so far.
+    pub fn dropped(&self) -> u64 {
+        self.ring.dropped()
+    }
+
+    // Set once the stream is gone, so nothing more will arrive.
+    pub fn is_closed(&self) -> bool {
+        self.ring.is_closed()
+    }
+}
+
+// What's left to the caller of Stream::on_samples, since its thread does the reading.
+pub struct SampleObserver {
+    ring: Arc<SampleRing>,
+}
+
+impl SampleObserver {
+    // Samples the observer fell too far behind to see.
+    pub fn dropped(&self) -> u64 {
+        self.ring.dropped()
+    }
+}
+
+pub struct Stream {
+    stream: cpal::Stream,
+    shared: Arc<Shared>,
+    playing: AtomicBool,
+}
 
 impl Stream {
     pub fn play(&self) {
-        self.0.play().unwrap();
+        self.stream.play().unwrap();
+        self.playing.store(true, Ordering::Relaxed);
+    }
+
+    pub fn pause(&self) {
+        self.stream.pause().unwrap();
+        self.playing.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+
+    // Master gain applied after the stream callback, safe to call while playing.
+    pub fn set_volume(&self, volume: f32) {
+        self.shared.volume.store(volume);
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.shared.volume.load()
+    }
+
+    // Peak output level, after the master volume, for driving a meter.
+    pub fn level(&self) -> f32 {
+        self.shared.level.load()
+    }
+
+    // How quickly level() falls after a peak, 0 for no hold at all.
+    pub fn set_meter_release(&self, secs: f32) {
+        self.shared.meter_release.store(secs);
+    }
+
+    // Observes the interleaved output after the master volume, e.g. for an oscilloscope.
+    // Samples go through a ring of `capacity` samples to a thread that calls `f` with whatever
+    // has arrived, so a slow observer drops samples rather than glitching playback. Only one
+    // observer can be registered per stream, later calls return None.
+    pub fn on_samples(&self, capacity: usize, mut f: impl FnMut(&[f32]) + Send + 'static) -> Option<SampleObserver> {
+        let consumer = self.sample_ring(capacity)?;
+        let observer = SampleObserver { ring: consumer.ring.clone() };
+        std::thread::spawn(move || {
+            let mut block = Vec::new();
+            loop {
+                // Checked before draining, so the last samples are still delivered.
+                let closed = consumer.is_closed();
+                block.extend(std::iter::from_fn(|| consumer.pop()));
+                if !block.is_empty() {
+                    f(&block);
+                    block.clear();
+                } else if closed {
+                    break;
+                } else {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            }
+        });
+        Some(observer)
+    }
+
+    // Like on_samples, but the caller drains the ring itself, e.g. once per UI frame.
+    pub fn sample_ring(&self, capacity: usize) -> Option<SampleConsumer> {
+        let ring = Arc::new(SampleRing::new(capacity));
+        self.shared.observer.set(ring.clone()).ok()?;
+        Some(SampleConsumer { ring })
     }
-}
\ No newline at end of file
+
+    // Stops playback and releases the device, the same as dropping but explicit.
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Stream {
+    // Pausing before the cpal stream is torn down avoids a final glitch from a partial buffer.
+    // Not every host supports pausing, so errors are ignored.
+    fn drop(&mut self) {
+        let _ = self.stream.pause();
+        if let Some(ring) = self.shared.observer.get() {
+            ring.closed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_error_describes_itself() {
+        let error: Box<dyn std::error::Error> = Box::new(ConfigError::NoOutputDevice);
+        assert_eq!(error.to_string(), "missing output device");
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn get_returns_rather_than_panicking() {
+        // Whether there's a device depends on the machine, but either way this must not panic.
+        match Config::get() {
+            Ok(config) => assert!(config.channels() > 0),
+            Err(error) => assert!(!error.to_string().is_empty()),
+        }
+    }
+
+    #[test]
+    fn convert_clamps_into_the_integer_range() {
+        let samples = [-2.0, -1.0, 0.0, 1.0, 2.0];
+        let mut signed = [0i16; 5];
+        convert(&samples, &mut signed);
+        assert_eq!(signed, [i16::MIN, i16::MIN, 0, i16::MAX, i16::MAX]);
+
+        let mut unsigned = [0u16; 5];
+        convert(&samples, &mut unsigned);
+        assert_eq!(unsigned[..2], [0, 0]);
+        assert_eq!(unsigned[3..], [u16::MAX, u16::MAX]);
+        assert!(unsigned[2].abs_diff(32768) <= 1);
+    }
+
+    #[test]
+    fn volume_round_trips_and_scales_output() {
+        let shared = Shared::new();
+        assert_eq!(shared.volume.load(), 1.0);
+        shared.volume.store(0.25);
+        assert_eq!(shared.volume.load(), 0.25);
+
+        let mut buf = [0.5; 8];
+        shared.process(&mut buf, 1000.0);
+        assert_eq!(buf, [0.125; 8]);
+    }
+
+    #[test]
+    #[ignore = "needs an audio device"]
+    fn list_devices_includes_the_default() {
+        let default = cpal::default_host().default_output_device().unwrap().name().unwrap();
+        assert!(Config::list_devices().contains(&default));
+        assert!(Config::with_device(&default).is_ok());
+        assert!(matches!(Config::with_device("no such device"), Err(ConfigError::NoSuchDevice(_))));
+    }
+
+    #[test]
+    fn unknown_hosts_are_an_error() {
+        assert!(matches!(Config::with_host_device("no such host", "default"), Err(ConfigError::NoSuchHost(_))));
+        assert!(matches!(Config::list_host_devices("no such host"), Err(ConfigError::NoSuchHost(_))));
+        assert!(!Config::list_hosts().is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs an audio device"]
+    fn builder_picks_the_nearest_sample_rate() {
+        let config = Config::builder().sample_rate(44100).build().unwrap();
+        let supported = cpal::default_host().default_output_device().unwrap()
+            .supported_output_configs().unwrap()
+            .any(|range| (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&44100));
+        if supported {
+            assert_eq!(config.sample_rate(), 44100);
+        } else {
+            assert!(config.sample_rate().abs_diff(44100) <= 4100, "{}", config.sample_rate());
+        }
+    }
+
+    #[test]
+    fn stream_errors_go_to_the_handler() {
+        // What cpal calls from the audio thread, say when a device is unplugged.
+        let errors = Arc::new(AtomicUsize::new(0));
+        let handled = errors.clone();
+        let mut on_error = error_callback(move |error| {
+            assert!(matches!(error, StreamError::DeviceNotAvailable));
+            handled.fetch_add(1, Ordering::Relaxed);
+        });
+        on_error(StreamError::DeviceNotAvailable);
+        assert_eq!(errors.load(Ordering::Relaxed), 1);
+
+        // The default only logs.
+        log_error(StreamError::DeviceNotAvailable);
+    }
+
+    #[test]
+    #[ignore = "needs an audio device"]
+    fn streams_can_be_started_and_stopped_repeatedly() {
+        let config = Config::get().unwrap();
+        for _ in 0..50 {
+            let stream = config.create_stream(|buf, _| buf.fill(0.0));
+            stream.play();
+            assert!(stream.is_playing());
+            stream.stop();
+        }
+        // Dropping without stopping is the same.
+        for _ in 0..50 {
+            config.create_stream(|buf, _| buf.fill(0.0)).play();
+        }
+    }
+
+    #[test]
+    fn meter_holds_the_peak_then_decays() {
+        let shared = Shared::new();
+        let mut buf = [0.5, -0.75, 0.25, 0.0];
+        shared.process(&mut buf, 1000.0);
+        assert_eq!(shared.level.load(), 0.75);
+
+        // Falls by 1/e over the release time, here 0.3s of 1000 samples a second.
+        let mut silence = [0.0; 300];
+        shared.process(&mut silence, 1000.0);
+        assert!((shared.level.load() - 0.75 / std::f32::consts::E).abs() < 1e-3, "{}", shared.level.load());
+
+        // Without release it just follows each buffer's peak.
+        shared.meter_release.store(0.0);
+        shared.process(&mut [0.1; 4], 1000.0);
+        assert_eq!(shared.level.load(), 0.1);
+
+        // After the master volume.
+        shared.volume.store(0.5);
+        shared.process(&mut [0.8; 4], 1000.0);
+        assert_eq!(shared.level.load(), 0.4);
+    }
+
+    #[test]
+    fn sample_ring_keeps_order_and_counts_drops() {
+        let shared = Shared::new();
+        let ring = Arc::new(SampleRing::new(4));
+        assert!(shared.observer.set(ring.clone()).is_ok());
+        let consumer = SampleConsumer { ring };
+        assert_eq!(consumer.pop(), None);
+
+        shared.process(&mut [0.1, 0.2, 0.3], 1000.0);
+        assert_eq!(consumer.pop(), Some(0.1));
+        // Wraps around the end of the slots, still oldest first.
+        shared.process(&mut [0.4, 0.5, 0.6], 1000.0);
+        let drained: Vec<f32> = std::iter::from_fn(|| consumer.pop()).collect();
+        assert_eq!(drained, [0.2, 0.3, 0.4, 0.5]);
+        assert_eq!(consumer.dropped(), 1);
+
+        assert!(!consumer.is_closed());
+        consumer.ring.closed.store(true, Ordering::Relaxed);
+        assert!(consumer.is_closed());
+    }
+}
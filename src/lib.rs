@@ -0,0 +1,7614 @@
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug)]
+pub enum Key {
+    A,
+    ASharp,
+    B,
+    C,
+    CSharp,
+    D,
+    DSharp,
+    E,
+    F,
+    FSharp,
+    G,
+    GSharp,
+}
+
+impl Key {
+    pub fn note(self, octave: i32) -> Note {
+        Note((octave - 4) * 12 + match self {
+            Self::C => -9,
+            Self::CSharp => -8,
+            Self::D => -7,
+            Self::DSharp => -6,
+            Self::E => -5,
+            Self::F => -4,
+            Self::FSharp => -3,
+            Self::G => -2,
+            Self::GSharp => -1,
+            Self::A => 0,
+            Self::ASharp => 1,
+            Self::B => 2,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Note(i32);
+
+impl Note {
+    // The octave range of MIDI notes, which covers anything audible.
+    pub const MIN_OCTAVE: i32 = -1;
+    pub const MAX_OCTAVE: i32 = 9;
+
+    // The most common tuning today: 12 tone equal temperament, A440.
+    pub fn hz(self) -> f32 {
+        self.hz_in(&Tuning::A440)
+    }
+
+    pub fn hz_in(self, tuning: &Tuning) -> f32 {
+        let equal = |note: Note| tuning.reference_hz * 2f32.powf(note.0 as f32 / 12.0);
+        let (tonic, ratios) = match &tuning.temperament {
+            Temperament::Equal => return equal(self),
+            Temperament::Just { tonic } => (*tonic, &JUST_RATIOS),
+            Temperament::Pythagorean { tonic } => (*tonic, &PYTHAGOREAN_RATIOS),
+        };
+        // The tonic keeps its equal tempered pitch, other notes are ratios from it.
+        let interval = self.0 - tonic.0;
+        let ratio = ratios[interval.rem_euclid(12) as usize];
+        equal(tonic) * ratio * 2f32.powi(interval.div_euclid(12))
+    }
+
+    pub fn sine(self) -> Sine<f32> {
+        sine(self.hz())
+    }
+
+    pub fn transpose(self, semitones: i32) -> Self {
+        Note(self.0 + semitones)
+    }
+
+    // MIDI note 69 is A4. MIDI only goes up to 127, so higher values are clamped.
+    pub fn from_midi(midi: u8) -> Self {
+        Note(midi.min(127) as i32 - 69)
+    }
+
+    // Clamped to the MIDI range 0..=127.
+    pub fn to_midi(self) -> u8 {
+        (self.0 + 69).clamp(0, 127) as u8
+    }
+}
+
+// Notes stacked on a root, as semitone intervals from it.
+#[derive(Clone, Debug)]
+pub struct Chord {
+    notes: Vec<Note>,
+}
+
+impl Chord {
+    pub fn from_intervals(root: Note, intervals: &[i32]) -> Self {
+        Self { notes: intervals.iter().map(|interval| Note(root.0 + interval)).collect() }
+    }
+
+    pub fn major(root: Note) -> Self {
+        Self::from_intervals(root, &[0, 4, 7])
+    }
+
+    pub fn minor(root: Note) -> Self {
+        Self::from_intervals(root, &[0, 3, 7])
+    }
+
+    pub fn dominant_seventh(root: Note) -> Self {
+        Self::from_intervals(root, &[0, 4, 7, 10])
+    }
+
+    pub fn major_seventh(root: Note) -> Self {
+        Self::from_intervals(root, &[0, 4, 7, 11])
+    }
+
+    pub fn minor_seventh(root: Note) -> Self {
+        Self::from_intervals(root, &[0, 3, 7, 10])
+    }
+
+    // Moves the lowest note up an octave, `count` times, e.g. 1 for first inversion.
+    pub fn invert(mut self, count: usize) -> Self {
+        for _ in 0..count {
+            if let Some(lowest) = self.notes.first().copied() {
+                self.notes.remove(0);
+                self.notes.push(Note(lowest.0 + 12));
+            }
+        }
+        self
+    }
+
+    pub fn notes(&self) -> &[Note] {
+        &self.notes
+    }
+}
+
+// The diatonic modes, each a rotation of the major scale's step pattern.
+#[derive(Copy, Clone, Debug)]
+pub enum Mode {
+    Major,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Minor,
+    Locrian,
+}
+
+impl Mode {
+    // Semitones above the tonic for each of the seven degrees.
+    pub fn offsets(self) -> [i32; 7] {
+        const MAJOR_STEPS: [i32; 7] = [2, 2, 1, 2, 2, 2, 1];
+        let rotation = match self {
+            Self::Major => 0,
+            Self::Dorian => 1,
+            Self::Phrygian => 2,
+            Self::Lydian => 3,
+            Self::Mixolydian => 4,
+            Self::Minor => 5,
+            Self::Locrian => 6,
+        };
+        let mut offsets = [0; 7];
+        for degree in 1..7 {
+            offsets[degree] = offsets[degree - 1] + MAJOR_STEPS[(degree - 1 + rotation) % 7];
+        }
+        offsets
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Scale {
+    tonic: Note,
+    mode: Mode,
+}
+
+impl Scale {
+    pub fn new(tonic: Note, mode: Mode) -> Self {
+        Self { tonic, mode }
+    }
+
+    // Degree 0 is the tonic, 7 the tonic an octave up, and negative degrees go down.
+    pub fn degree(&self, degree: i32) -> Note {
+        let octave = degree.div_euclid(7);
+        let offset = self.mode.offsets()[degree.rem_euclid(7) as usize];
+        Note(self.tonic.0 + octave * 12 + offset)
+    }
+
+    pub fn contains(&self, note: Note) -> bool {
+        let offset = (note.0 - self.tonic.0).rem_euclid(12);
+        self.mode.offsets().contains(&offset)
+    }
+
+    // The scale note closest in pitch to hz, in equal temperament. Exactly halfway between two
+    // notes goes to the lower one.
+    pub fn nearest(&self, hz: f32) -> Note {
+        let semitones = 12.0 * (hz / Tuning::A440.reference_hz).log2();
+        // No mode has a gap wider than two semitones, so the nearest note is within that.
+        let below = semitones.floor() as i32;
+        (below - 2..=below + 3)
+            .map(Note)
+            .filter(|&note| self.contains(note))
+            .min_by(|a, b| (a.0 as f32 - semitones).abs().total_cmp(&(b.0 as f32 - semitones).abs()))
+            .unwrap_or(self.tonic)
+    }
+
+    // Ascends forever from the tonic, use take() to bound it.
+    pub fn notes(self) -> impl Iterator<Item=Note> {
+        (0..).map(move |degree| self.degree(degree))
+    }
+}
+
+pub enum Temperament {
+    Equal,
+    // Ratios are relative to the tonic, so it sounds right in that key.
+    Just { tonic: Note },
+    Pythagorean { tonic: Note },
+}
+
+pub struct Tuning {
+    // The pitch of A4.
+    pub reference_hz: f32,
+    pub temperament: Temperament,
+}
+
+impl Tuning {
+    pub const A440: Tuning = Tuning { reference_hz: 440.0, temperament: Temperament::Equal };
+}
+
+// 5-limit just intonation, from the unison to the major seventh.
+pub const JUST_RATIOS: [f32; 12] = [
+    1.0, 16.0 / 15.0, 9.0 / 8.0, 6.0 / 5.0, 5.0 / 4.0, 4.0 / 3.0,
+    45.0 / 32.0, 3.0 / 2.0, 8.0 / 5.0, 5.0 / 3.0, 9.0 / 5.0, 15.0 / 8.0,
+];
+
+// Stacked 3:2 fifths, folded into one octave.
+pub const PYTHAGOREAN_RATIOS: [f32; 12] = [
+    1.0, 256.0 / 243.0, 9.0 / 8.0, 32.0 / 27.0, 81.0 / 64.0, 4.0 / 3.0,
+    729.0 / 512.0, 3.0 / 2.0, 128.0 / 81.0, 27.0 / 16.0, 16.0 / 9.0, 243.0 / 128.0,
+];
+
+#[derive(Debug)]
+pub enum ParseNoteError {
+    Empty,
+    InvalidKey(char),
+    MissingOctave,
+    InvalidOctave(String),
+    OctaveOutOfRange(i32),
+}
+
+impl std::fmt::Display for ParseNoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty note name"),
+            Self::InvalidKey(key) => write!(f, "invalid key {key:?}, expected A to G"),
+            Self::MissingOctave => write!(f, "missing octave"),
+            Self::InvalidOctave(octave) => write!(f, "invalid octave {octave:?}"),
+            Self::OctaveOutOfRange(octave) => write!(f, "octave {octave} out of range {}..={}", Note::MIN_OCTAVE, Note::MAX_OCTAVE),
+        }
+    }
+}
+
+impl std::error::Error for ParseNoteError {}
+
+// Scientific pitch notation, e.g. "A4", "C#5" or "Gb3".
+impl std::str::FromStr for Note {
+    type Err = ParseNoteError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let key = match chars.next().ok_or(ParseNoteError::Empty)? {
+            'A' => Key::A,
+            'B' => Key::B,
+            'C' => Key::C,
+            'D' => Key::D,
+            'E' => Key::E,
+            'F' => Key::F,
+            'G' => Key::G,
+            key => return Err(ParseNoteError::InvalidKey(key)),
+        };
+        let rest = chars.as_str();
+        let (accidental, octave) = if let Some(octave) = rest.strip_prefix('#') {
+            (1, octave)
+        } else if let Some(octave) = rest.strip_prefix('b') {
+            (-1, octave)
+        } else {
+            (0, rest)
+        };
+        if octave.is_empty() {
+            return Err(ParseNoteError::MissingOctave);
+        }
+        let octave: i32 = octave.parse().map_err(|_| ParseNoteError::InvalidOctave(octave.to_string()))?;
+        if !(Note::MIN_OCTAVE..=Note::MAX_OCTAVE).contains(&octave) {
+            return Err(ParseNoteError::OctaveOutOfRange(octave));
+        }
+        // Flats (and sharps) are just a semitone shift, so e.g. Gb lands on F#, and Cb on the
+        // B of the octave below.
+        Ok(Note(key.note(octave).0 + accidental))
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SampleTime {
+    // 64 bits so absolute times never run out, even counted in microseconds.
+    pub count: u64,
+    pub rate: u32,
+}
+
+impl SampleTime {
+    // Fine for the short elapsed times passed to update, but f32 only has 24 bits, so long
+    // absolute times should use as_secs_f64 or compare SampleTimes directly.
+    pub fn as_secs(&self) -> f32 {
+        self.as_secs_f64() as f32
+    }
+
+    pub fn as_secs_f64(&self) -> f64 {
+        self.count as f64 / self.rate as f64
+    }
+
+    // Microseconds, for lengths that are known in seconds rather than samples.
+    pub fn from_secs(secs: f32) -> Self {
+        Self::from_secs_f64(secs as f64)
+    }
+
+    pub fn from_secs_f64(secs: f64) -> Self {
+        const RATE: u32 = 1_000_000;
+        Self { count: (secs.max(0.0) * RATE as f64).round() as u64, rate: RATE }
+    }
+
+    // The first sample at or after this time at another rate, e.g. to know how many to render.
+    pub fn samples_at(&self, rate: u32) -> u64 {
+        (self.count as u128 * rate as u128).div_ceil(self.rate as u128) as u64
+    }
+}
+
+// The longer of two optional lengths, where None is endless.
+fn max_length(a: Option<SampleTime>, b: Option<SampleTime>) -> Option<SampleTime> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a < b { b } else { a }),
+        _ => None,
+    }
+}
+
+// The shorter of two optional lengths, where None is endless.
+fn min_length(a: Option<SampleTime>, b: Option<SampleTime>) -> Option<SampleTime> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b < a { b } else { a }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+impl PartialEq for SampleTime {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for SampleTime {}
+
+impl PartialOrd for SampleTime {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SampleTime {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // A/B < C/D <=> A*D < C*B
+        // Widened so the products can't overflow, which is exact for any count and rate.
+        (self.count as u128 * other.rate as u128).cmp(&(other.count as u128 * self.rate as u128))
+    }
+}
+
+// A sample that can be written to an interleaved device frame. Mono samples fill every channel,
+// multichannel samples duplicate their last channel if the device has more, and drop extras
+// if it has fewer.
+pub trait Frame {
+    // How many channels the frame has itself, such as in a WAV file, rather than when
+    // written to a device's channels.
+    const CHANNELS: usize;
+
+    fn write_to(&self, channels: &mut [f32]);
+}
+
+impl Frame for f32 {
+    const CHANNELS: usize = 1;
+
+    fn write_to(&self, channels: &mut [f32]) {
+        channels.fill(*self);
+    }
+}
+
+impl<const N: usize> Frame for [f32; N] {
+    const CHANNELS: usize = N;
+
+    fn write_to(&self, channels: &mut [f32]) {
+        let len = channels.len().min(N);
+        let (head, tail) = channels.split_at_mut(len);
+        head.copy_from_slice(&self[..len]);
+        if let Some(&last) = self.last() {
+            tail.fill(last);
+        }
+    }
+}
+
+pub trait Source {
+    type Sample;
+
+    fn wrap(self) -> Wrapped<Self> where Self: Sized {
+        Wrapped(self)
+    }
+
+    fn update(&mut self, _elapsed: SampleTime) {}
+
+    fn sample(&self) -> Self::Sample;
+
+    // True once every sample after the current one will be silent.
+    fn finished(&self) -> bool {
+        false
+    }
+
+    // Renders consecutive samples at `rate`. Composite sources can override this to process
+    // a block at a time instead of walking the whole tree per sample.
+    fn fill(&mut self, out: &mut [Self::Sample], rate: u32) {
+        for out in out {
+            self.update(SampleTime { count: 1, rate });
+            *out = self.sample();
+        }
+    }
+
+    // How long until the source goes silent for good, or None if it never does or can't know,
+    // such as for oscillators or feedback tails that only decay towards silence.
+    fn length(&self) -> Option<SampleTime> {
+        None
+    }
+
+    // Returns to the state it was constructed in, so the same graph can be played again.
+    // Closures given to map() keep any state of their own.
+    fn reset(&mut self) {}
+
+    // Fast forwards, e.g. to start rendering part way through. Steps one sample at a time like
+    // rendering does, so the state afterwards is the same as if the output had been used.
+    fn advance(&mut self, duration: SampleTime) {
+        for _ in 0..duration.count {
+            self.update(SampleTime { count: 1, rate: duration.rate });
+        }
+    }
+}
+
+// Effects and other combinators, on every Source through the blanket impl below.
+pub trait SourceExt: Source + Sized {
+    // Applies f to each sample. Send + 'static so it can run in the stream callback.
+    fn map<F>(self, f: F) -> Map<Self, F>
+        where F: FnMut(f32) -> f32 + Send + 'static,
+    {
+        Map { source: self, f, output: 0.0 }
+    }
+
+    fn gain<Gain>(self, gain: Gain) -> Mul<Self, Gain> {
+        Mul { left: self, right: gain }
+    }
+
+    // For frequency sources, shifts their pitch, so e.g. sine(glide(...).transpose(7.0))
+    // plays a fifth up. Equal tempered, so 12 exactly doubles.
+    fn transpose(self, semitones: f32) -> Mul<Self, f32> {
+        Mul { left: self, right: 2f32.powf(semitones / 12.0) }
+    }
+
+    // For frequency sources, snaps to the nearest note of scale, e.g. to play a sample_hold
+    // as a melody.
+    fn quantize(self, scale: Scale) -> Quantize<Self> {
+        Quantize { source: self, scale, hz: 0.0 }
+    }
+
+    // Amplitude modulation dipping the gain by up to depth, in 0..=1, starting at full gain.
+    fn tremolo<Hz>(self, hz: Hz, depth: f32) -> Mul<Self, Lfo<Hz>> {
+        // A quarter period back from the sine's zero crossing is its trough, so the gain
+        // is 1 - depth * (0.5 - 0.5 * cos(2 pi hz t)).
+        let gain = lfo(hz, Waveform::Sine).with_range(1.0, 1.0 - depth).with_phase(0.75);
+        Mul { left: self, right: gain }
+    }
+
+    // Ring modulation: multiplying by a bipolar (-1..1) carrier swaps each frequency for its
+    // sum and difference with the carrier's, e.g. 440Hz by 100Hz gives 340Hz and 540Hz and no
+    // 440Hz. Unlike tremolo, whose modulator is unipolar so the original frequency remains.
+    fn ring_mod<Carrier>(self, carrier: Carrier) -> Mul<Self, Carrier> {
+        Mul { left: self, right: carrier }
+    }
+
+    // Quantizes to 2^bits levels, holding each value for `downsample` samples.
+    fn bitcrush(self, bits: u32, downsample: u32) -> Crush<Self> {
+        Crush { source: self, bits, downsample: downsample.max(1), counter: 0, held: 0.0 }
+    }
+
+    // Overdrive: scales the input by amount, then shapes it into -1..=1.
+    fn distort(self, amount: f32, kind: ShaperKind) -> Shaper<Self> {
+        Shaper { source: self, amount, kind }
+    }
+
+    // Mix of 0 is entirely self, 1 entirely other. Equal power unless made linear().
+    fn crossfade<B, Mix>(self, other: B, mix: Mix) -> Crossfade<Self, B, Mix> {
+        Crossfade { a: self, b: other, mix, law: FadeLaw::EqualPower }
+    }
+
+    fn dc_block(self) -> DcBlock<Self> {
+        DcBlock { source: self, x1: None, y1: 0.0 }
+    }
+
+    fn fade_in(self, secs: f32) -> Fade<Self> {
+        fade(self, secs, f32::INFINITY, 0.0)
+    }
+
+    // Fades to silence over secs, starting at time.
+    fn fade_out_at(self, time: f32, secs: f32) -> Fade<Self> {
+        fade(self, 0.0, time, secs)
+    }
+
+    // Plays for duration_secs, then is silent and finished.
+    fn one_shot(self, duration_secs: f32) -> OneShot<Self> {
+        OneShot { source: self, duration_secs, samples: 0, playing: false, finished: false }
+    }
+
+    // Freeverb style: room and damp in 0..=1, and wet mix where 0 is the dry source untouched.
+    fn reverb(self, room: f32, damp: f32, wet: f32) -> Reverb<Self> {
+        Reverb { source: self, room, damp, wet, rate: 0, combs: Vec::new(), allpasses: Vec::new(), output: 0.0 }
+    }
+
+    // Convolves with an impulse response, in samples at the rendering rate, e.g. a recorded
+    // room. There's no added latency: the IR's head is applied directly, and only the rest goes
+    // through FFT blocks.
+    fn convolve(self, ir: Vec<f32>) -> ConvolutionReverb<Self> {
+        ConvolutionReverb::new(self, ir)
+    }
+
+    // Threshold is a linear amplitude, and ratio can be f32::INFINITY for limiting.
+    fn compress(self, threshold: f32, ratio: f32, attack_secs: f32, release_secs: f32) -> Compressor<Self> {
+        Compressor { source: self, threshold, ratio, follower: follower(attack_secs, release_secs), output: 0.0 }
+    }
+
+    // Fast infinite ratio compression, to keep the master bus under threshold.
+    fn limit(self, threshold: f32) -> Compressor<Self> {
+        self.compress(threshold, f32::INFINITY, 0.001, 0.05)
+    }
+
+    // Bounds given the wrong way round are swapped, and a NaN bound leaves that side
+    // unlimited, rather than panicking in f32::clamp on every sample.
+    fn clamp_range(self, min: f32, max: f32) -> Clamp<Self> {
+        let min = if min.is_nan() { f32::NEG_INFINITY } else { min };
+        let max = if max.is_nan() { f32::INFINITY } else { max };
+        Clamp { source: self, min: min.min(max), max: max.max(min) }
+    }
+
+    fn tanh_saturate(self) -> Saturate<Self> {
+        Saturate { source: self }
+    }
+
+    fn low_pass<Cutoff>(self, cutoff: Cutoff) -> LowPass<Self, Cutoff> {
+        LowPass { source: self, cutoff, filter: Biquad::new() }
+    }
+
+    fn high_pass<Cutoff>(self, cutoff: Cutoff, q: f32) -> HighPass<Self, Cutoff> {
+        HighPass { source: self, cutoff, q: q.max(MIN_Q), filter: Biquad::new() }
+    }
+
+    fn band_pass<Center>(self, center: Center, q: f32) -> BandPass<Self, Center> {
+        BandPass { source: self, center, q: q.max(MIN_Q), filter: Biquad::new() }
+    }
+
+    // Mono sources are lifted to stereo by panning them, `.pan(0.0)` for centered.
+    fn pan<Position>(self, position: Position) -> Pan<Self, Position> {
+        Pan { source: self, position }
+    }
+
+    // Sweeps the pan position with an LFO of any waveform, between -depth and depth. Depth is
+    // 0..=1, and 0 stays centered.
+    fn auto_pan<Hz>(self, hz: Hz, depth: f32, shape: Waveform) -> AutoPan<Self, Hz> {
+        let depth = depth.clamp(0.0, 1.0);
+        self.pan(lfo(hz, shape).with_range(-depth, depth))
+    }
+
+    // Averages every channel of a multichannel source down to one.
+    fn to_mono(self) -> ToMono<Self> {
+        ToMono { source: self }
+    }
+
+    // The same mono signal on both channels, at full level unlike `.pan(0.0)`.
+    fn to_stereo(self) -> ToStereo<Self> {
+        ToStereo { source: self }
+    }
+
+    // Stereo width via mid/side: 0 is mono, 1 unchanged, and above 1 exaggerated. Only side
+    // is scaled, so the mono sum L + R is the same at any width.
+    fn widen(self, width: f32) -> Widen<Self> {
+        Widen { source: self, width }
+    }
+
+    // Passes audio through unchanged, keeping the last `size` samples (rounded up to a power
+    // of two) for Spectrum::magnitudes().
+    fn spectrum(self, size: usize) -> Spectrum<Self> {
+        let size = size.max(2).next_power_of_two();
+        Spectrum { source: self, history: DelayLine::new(size), rate: 0 }
+    }
+
+    // Mono to stereo thickening from `voices` modulated delays around 20ms, the right channel
+    // sweeping a quarter cycle behind the left. Depth in 0..=1 sweeps up to 10ms either way.
+    fn chorus(self, rate: f32, depth: f32, mix: f32, voices: usize) -> Chorus<Self> {
+        Chorus {
+            source: self,
+            rate,
+            depth: depth.clamp(0.0, 1.0),
+            mix,
+            phases: (0..voices.max(1)).map(|voice| voice as f32 / voices.max(1) as f32).collect(),
+            sample_rate: 0,
+            delay: DelayLine::new(1),
+            output: [0.0; 2],
+        }
+    }
+
+    // Jet-like sweep from a 1 to 10ms delay mixed with the dry signal. Depth in 0..=1 scales how
+    // far up that range it sweeps, and negative feedback moves the notches to the peaks.
+    fn flanger(self, rate: f32, depth: f32, feedback: f32, mix: f32) -> Flanger<Self> {
+        Flanger {
+            source: self,
+            rate,
+            depth: depth.clamp(0.0, 1.0),
+            feedback: feedback.clamp(-MAX_FEEDBACK, MAX_FEEDBACK),
+            mix,
+            phase: 0.0,
+            sample_rate: 0,
+            delay: DelayLine::new(1),
+            output: 0.0,
+        }
+    }
+
+    // Runs this source and then `shape` at `factor` times the rate, low passing before
+    // dropping back down, so harmonics a nonlinearity adds above Nyquist are removed rather
+    // than aliasing, e.g. .oversample(4, |x| ShaperKind::HardClip.shape(x * 4.0))
+    fn oversample<F>(self, factor: u32, shape: F) -> Oversample<Self, F>
+        where F: FnMut(f32) -> f32,
+    {
+        Oversample { source: self, factor: factor.max(1), shape, rate: 0, filters: std::array::from_fn(|_| Biquad::new()), output: 0.0 }
+    }
+
+    // An empty equalizer, add bands with peak(), low_shelf() and high_shelf().
+    fn equalizer(self) -> Equalizer<Self> {
+        Equalizer { source: self, bands: Vec::new(), rate: 0, output: 0.0 }
+    }
+
+    // Mono to stereo width from the precedence effect: the lagging side is delayed by 1 to
+    // 30ms, which is heard as width with the sound placed towards the leading side, rather
+    // than as an echo.
+    fn haas(self, delay_secs: f32, lead: Side) -> Haas<Self> {
+        Haas { source: self, delay_secs: delay_secs.clamp(0.001, 0.03), lead, rate: 0, delay: DelayLine::new(1), output: [0.0; 2] }
+    }
+
+    // Echoes at fixed (delay_secs, gain) taps, all read from one buffer, without feedback.
+    // The dry signal is always included.
+    fn multi_tap(self, taps: Vec<(f32, f32)>) -> MultiTapDelay<Self> {
+        MultiTapDelay { source: self, taps, rate: 0, offsets: Vec::new(), delay: DelayLine::new(1), output: 0.0 }
+    }
+
+    // Shifts pitch without changing duration, with two read heads sweeping through a short
+    // delay and crossfading where they jump. A shift of 0 passes the source through untouched.
+    fn pitch_shift(self, semitones: f32) -> PitchShift<Self> {
+        PitchShift {
+            source: self,
+            ratio: 2f32.powf(semitones / 12.0),
+            rate: 0,
+            delay: DelayLine::new(1),
+            phase: 0.0,
+            output: 0.0,
+        }
+    }
+
+    // Mono to stereo echoes alternating sides, starting on the left.
+    fn ping_pong(self, delay_secs: f32, feedback: f32, mix: f32) -> PingPong<Self> {
+        PingPong {
+            source: self,
+            delay_secs,
+            feedback: feedback.min(MAX_FEEDBACK),
+            mix,
+            rate: 0,
+            left: DelayLine::new(1),
+            right: DelayLine::new(1),
+            output: [0.0; 2],
+        }
+    }
+
+    // Silences the source while its level is under threshold, fading in over attack_secs when
+    // it opens and out over release_secs when it closes. It then stays open until the level
+    // falls under half the threshold, so a level hovering around it doesn't chatter.
+    fn noise_gate(self, threshold: f32, attack_secs: f32, release_secs: f32) -> Gate<Self> {
+        Gate {
+            source: self,
+            open_threshold: threshold,
+            close_threshold: threshold / 2.0,
+            level: follower(0.001, 0.02),
+            gain: follower(attack_secs, release_secs),
+            open: false,
+            output: 0.0,
+        }
+    }
+
+    // For control sources, glides exponentially toward each new value instead of stepping,
+    // covering about 63% of the way in time_secs. Starts at the first value, without a glide.
+    fn smoothed(self, time_secs: f32) -> Smoothed<Self> {
+        Smoothed { target: self, time_secs, value: None }
+    }
+
+    // The smoothed level of this source rather than its audio, for driving other parameters
+    // such as ducking another source's gain.
+    fn envelope_follower(self, attack_secs: f32, release_secs: f32) -> EnvFollower<Self> {
+        EnvFollower { source: self, follower: follower(attack_secs, release_secs) }
+    }
+
+    fn echo(self, delay_secs: f32, feedback: f32) -> Echo<Self> {
+        Echo {
+            source: self,
+            delay_secs,
+            feedback: feedback.min(MAX_FEEDBACK),
+            rate: 0,
+            buffer: Vec::new(),
+            pos: 0,
+            output: 0.0,
+        }
+    }
+
+    // An echo timed to the music, e.g. Division::note(8).dotted() at 120bpm.
+    fn synced_echo(self, division: Division, bpm: f32, feedback: f32) -> Echo<Self> {
+        self.echo(division.secs(bpm), feedback)
+    }
+}
+
+impl<S: Source> SourceExt for S {}
+
+impl Source for f32 {
+    type Sample = Self;
+
+    fn sample(&self) -> Self::Sample {
+        *self
+    }
+}
+
+pub struct Wrapped<T>(T);
+
+impl<T> Wrapped<T> {
+    pub fn unwrap(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Source for Wrapped<T>
+    where T: Source
+{
+    type Sample = T::Sample;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.0.update(elapsed)
+    }
+
+    fn sample(&self) -> Self::Sample {
+        self.0.sample()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.0.length()
+    }
+
+    fn reset(&mut self) {
+        self.0.reset();
+    }
+
+    fn finished(&self) -> bool {
+        self.0.finished()
+    }
+
+    fn fill(&mut self, out: &mut [Self::Sample], rate: u32) {
+        self.0.fill(out, rate)
+    }
+}
+
+// Unsized too, so boxed trait objects can be chained like any other source.
+impl<T> Source for Box<T>
+    where T: Source + ?Sized
+{
+    type Sample = T::Sample;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        (**self).update(elapsed)
+    }
+
+    fn sample(&self) -> Self::Sample {
+        (**self).sample()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        (**self).length()
+    }
+
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+
+    fn finished(&self) -> bool {
+        (**self).finished()
+    }
+
+    fn fill(&mut self, out: &mut [Self::Sample], rate: u32) {
+        (**self).fill(out, rate)
+    }
+}
+
+// Lets a source be rendered by reference and then be used again.
+impl<T> Source for &mut T
+    where T: Source + ?Sized
+{
+    type Sample = T::Sample;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        (**self).update(elapsed)
+    }
+
+    fn sample(&self) -> Self::Sample {
+        (**self).sample()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        (**self).length()
+    }
+
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+
+    fn finished(&self) -> bool {
+        (**self).finished()
+    }
+
+    fn fill(&mut self, out: &mut [Self::Sample], rate: u32) {
+        (**self).fill(out, rate)
+    }
+}
+
+// f32 is already a constant Source, so this is mostly for other sample types, such as
+// konst([0.5, 0.25]) for a stereo constant.
+pub struct Const<T> {
+    value: T,
+}
+
+impl<T: Clone> Source for Const<T> {
+    type Sample = T;
+
+    fn sample(&self) -> T {
+        self.value.clone()
+    }
+}
+
+pub fn konst<T>(value: T) -> Const<T> {
+    Const { value }
+}
+
+// Moves an oscillator's phase on, keeping it in 0..1 even when modulation takes hz negative,
+// which then runs the waveform backwards. Above Nyquist a waveform can't be represented, and
+// would alias to some unrelated lower pitch, so hz is clamped there instead.
+fn advance_phase(phase: f32, hz: f32, elapsed: SampleTime) -> f32 {
+    let nyquist = elapsed.rate as f32 / 2.0;
+    let phase = (phase + elapsed.as_secs() * hz.clamp(-nyquist, nyquist)).rem_euclid(1.0);
+    // A tiny negative phase rounds up to exactly 1.
+    if phase < 1.0 { phase } else { 0.0 }
+}
+
+pub struct Sine<Hz> {
+    hz: Hz,
+    phase: f32,
+    start_phase: f32,
+    // Added to phase when sampling, so it stays put under frequency modulation.
+    offset: f32,
+}
+
+impl<Hz> Sine<Hz> {
+    pub fn vibrato<VibHz>(self, hz: VibHz, cents: f32) -> Sine<Mul<Hz, Vibrato<VibHz>>> {
+        Sine { hz: Mul { left: self.hz, right: vibrato(hz, cents) }, phase: self.phase, start_phase: self.start_phase, offset: self.offset }
+    }
+
+    pub fn phase_offset(self, offset: f32) -> Self {
+        Self { offset, ..self }
+    }
+
+    // The current sample with radians added to the phase, for phase modulation.
+    fn sample_shifted(&self, radians: f32) -> f32 {
+        ((self.phase + self.offset) * std::f32::consts::TAU + radians).sin()
+    }
+
+    // True phase modulation: modulator, taken as bipolar -1..=1, is scaled by depth radians and
+    // added straight to the phase when sampling. Unlike vibrato, which bends hz and so moves
+    // the phase by the integral of the modulator, a constant modulator here is only a fixed
+    // phase shift.
+    pub fn phase_modulate<M>(self, modulator: M, depth: f32) -> PmSine<Hz, M> {
+        PmSine { sine: self, modulator, depth }
+    }
+
+    pub fn unison(self, count: usize, spread_cents: f32) -> Unison<Sine<Mul<Hz, f32>>> where Hz: Clone {
+        unison(count, spread_cents, |ratio| Sine { hz: Mul { left: self.hz.clone(), right: ratio }, phase: self.phase, start_phase: self.start_phase, offset: self.offset })
+    }
+
+    // Restarts this oscillator's cycle every time a master oscillator at master_hz wraps.
+    pub fn hard_sync<MasterHz>(self, master_hz: MasterHz) -> HardSync<Hz, MasterHz> {
+        HardSync { slave: self, master: sine(master_hz) }
+    }
+}
+
+impl<Hz> Source for Sine<Hz>
+    where Hz: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.hz.update(elapsed);
+        self.phase = advance_phase(self.phase, self.hz.sample(), elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        self.sample_shifted(0.0)
+    }
+
+    fn reset(&mut self) {
+        self.hz.reset();
+        self.phase = self.start_phase;
+    }
+}
+
+pub fn sine<Hz>(hz: Hz) -> Sine<Hz> {
+    sine_with_phase(hz, 0.0)
+}
+
+pub fn sine_with_phase<Hz>(hz: Hz, phase: f32) -> Sine<Hz> {
+    Sine { hz, phase, start_phase: phase, offset: 0.0 }
+}
+
+// Reads a single cycle table with linear interpolation instead of computing the waveform.
+// The table is shared, so many voices can use the same one.
+pub struct Wavetable<Hz> {
+    table: std::sync::Arc<[f32]>,
+    hz: Hz,
+    phase: f32,
+}
+
+impl<Hz> Source for Wavetable<Hz>
+    where Hz: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.hz.update(elapsed);
+        self.phase = advance_phase(self.phase, self.hz.sample(), elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        let len = self.table.len();
+        if len == 0 {
+            return 0.0;
+        }
+        let position = self.phase * len as f32;
+        let index = position as usize % len;
+        let current = self.table[index];
+        let next = self.table[(index + 1) % len];
+        current + (next - current) * position.fract()
+    }
+
+    fn reset(&mut self) {
+        self.hz.reset();
+        self.phase = 0.0;
+    }
+}
+
+pub fn wavetable<Hz>(table: impl Into<std::sync::Arc<[f32]>>, hz: Hz) -> Wavetable<Hz> {
+    Wavetable { table: table.into(), hz, phase: 0.0 }
+}
+
+// One cycle of a sum of sine harmonics, where amplitudes[0] is the fundamental.
+pub fn harmonic_table(len: usize, amplitudes: &[f32]) -> Vec<f32> {
+    (0..len)
+        .map(|index| {
+            let phase = index as f32 / len as f32;
+            amplitudes.iter().enumerate()
+                .map(|(harmonic, amplitude)| amplitude * (phase * (harmonic + 1) as f32 * std::f32::consts::TAU).sin())
+                .sum()
+        })
+        .collect()
+}
+
+pub fn sine_table(len: usize) -> Vec<f32> {
+    harmonic_table(len, &[1.0])
+}
+
+// Band limited to the given number of harmonics, unlike Saw.
+pub fn saw_table(len: usize, harmonics: usize) -> Vec<f32> {
+    // The Fourier series of the same 2 * phase - 1 ramp: -2/pi * sum(sin(k x) / k)
+    let amplitudes: Vec<f32> = (1..=harmonics)
+        .map(|k| -2.0 / (std::f32::consts::PI * k as f32))
+        .collect();
+    harmonic_table(len, &amplitudes)
+}
+
+// Stacked copies of an oscillator, detuned evenly across -spread_cents..=spread_cents and
+// mixed at equal gain.
+pub struct Unison<O> {
+    voices: Vec<O>,
+}
+
+impl<O> Source for Unison<O>
+    where O: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        for voice in &mut self.voices {
+            voice.update(elapsed);
+        }
+    }
+
+    fn sample(&self) -> f32 {
+        let sum: f32 = self.voices.iter().map(|voice| voice.sample()).sum();
+        sum / self.voices.len().max(1) as f32
+    }
+
+    fn reset(&mut self) {
+        for voice in &mut self.voices {
+            voice.reset();
+        }
+    }
+}
+
+// Builds each voice from its frequency ratio, so it works with any pitched source.
+pub fn unison<O>(count: usize, spread_cents: f32, mut voice: impl FnMut(f32) -> O) -> Unison<O> {
+    let voices = (0..count)
+        .map(|index| {
+            let cents = if count > 1 {
+                spread_cents * (2.0 * index as f32 / (count - 1) as f32 - 1.0)
+            } else {
+                0.0
+            };
+            voice(2f32.powf(cents / 1200.0))
+        })
+        .collect();
+    Unison { voices }
+}
+
+pub struct HardSync<Hz, MasterHz> {
+    slave: Sine<Hz>,
+    master: Sine<MasterHz>,
+}
+
+impl<Hz, MasterHz> Source for HardSync<Hz, MasterHz>
+    where Hz: Source<Sample=f32>,
+          MasterHz: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        let master_phase = self.master.phase;
+        self.master.update(elapsed);
+        self.slave.update(elapsed);
+        if self.master.phase < master_phase {
+            self.slave.phase = 0.0;
+        }
+    }
+
+    fn sample(&self) -> f32 {
+        self.slave.sample()
+    }
+
+    fn reset(&mut self) {
+        self.slave.reset();
+        self.master.reset();
+    }
+}
+
+// Frequency ratio swinging between 2^(-cents/1200) and 2^(cents/1200), to multiply a carrier
+// frequency with so the depth is the same number of cents at any pitch.
+pub struct Vibrato<Hz> {
+    lfo: Sine<Hz>,
+    cents: f32,
+}
+
+impl<Hz> Source for Vibrato<Hz>
+    where Hz: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.lfo.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        2f32.powf(self.cents * self.lfo.sample() / 1200.0)
+    }
+
+    fn reset(&mut self) {
+        self.lfo.reset();
+    }
+}
+
+pub fn vibrato<Hz>(hz: Hz, cents: f32) -> Vibrato<Hz> {
+    Vibrato { lfo: sine(hz), cents }
+}
+
+pub struct Square<Hz, Duty> {
+    hz: Hz,
+    // Fraction of each period spent high, in 0..1
+    duty: Duty,
+    phase: f32,
+}
+
+impl<Hz, Duty> Square<Hz, Duty> {
+    pub fn with_duty<NewDuty>(self, duty: NewDuty) -> Square<Hz, NewDuty> {
+        Square { hz: self.hz, duty, phase: self.phase }
+    }
+}
+
+impl<Hz, Duty> Source for Square<Hz, Duty>
+    where Hz: Source<Sample=f32>,
+          Duty: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.hz.update(elapsed);
+        self.duty.update(elapsed);
+        self.phase = advance_phase(self.phase, self.hz.sample(), elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        if self.phase < self.duty.sample() { 1.0 } else { -1.0 }
+    }
+
+    fn reset(&mut self) {
+        self.hz.reset();
+        self.duty.reset();
+        self.phase = 0.0;
+    }
+}
+
+pub fn square<Hz>(hz: Hz) -> Square<Hz, f32> {
+    Square { hz, duty: 0.5, phase: 0.0 }
+}
+
+pub struct Saw<Hz> {
+    hz: Hz,
+    phase: f32,
+}
+
+impl<Hz> Saw<Hz> {
+    pub fn vibrato<VibHz>(self, hz: VibHz, cents: f32) -> Saw<Mul<Hz, Vibrato<VibHz>>> {
+        Saw { hz: Mul { left: self.hz, right: vibrato(hz, cents) }, phase: self.phase }
+    }
+
+    pub fn unison(self, count: usize, spread_cents: f32) -> Unison<Saw<Mul<Hz, f32>>> where Hz: Clone {
+        unison(count, spread_cents, |ratio| Saw { hz: Mul { left: self.hz.clone(), right: ratio }, phase: self.phase })
+    }
+}
+
+impl<Hz> Source for Saw<Hz>
+    where Hz: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.hz.update(elapsed);
+        self.phase = advance_phase(self.phase, self.hz.sample(), elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        2.0 * self.phase - 1.0
+    }
+
+    fn reset(&mut self) {
+        self.hz.reset();
+        self.phase = 0.0;
+    }
+}
+
+pub fn saw<Hz>(hz: Hz) -> Saw<Hz> {
+    Saw { hz, phase: 0.0 }
+}
+
+pub struct Triangle<Hz> {
+    hz: Hz,
+    phase: f32,
+}
+
+impl<Hz> Triangle<Hz> {
+    pub fn vibrato<VibHz>(self, hz: VibHz, cents: f32) -> Triangle<Mul<Hz, Vibrato<VibHz>>> {
+        Triangle { hz: Mul { left: self.hz, right: vibrato(hz, cents) }, phase: self.phase }
+    }
+}
+
+impl<Hz> Source for Triangle<Hz>
+    where Hz: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.hz.update(elapsed);
+        self.phase = advance_phase(self.phase, self.hz.sample(), elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        // -1 at phase 0, rising to 1 at phase 0.5, falling back to -1.
+        1.0 - 4.0 * (self.phase - 0.5).abs()
+    }
+
+    fn reset(&mut self) {
+        self.hz.reset();
+        self.phase = 0.0;
+    }
+}
+
+pub fn triangle<Hz>(hz: Hz) -> Triangle<Hz> {
+    Triangle { hz, phase: 0.0 }
+}
+
+// Polynomial band-limited step: the residual between an ideal band-limited step and a naive
+// one, for a discontinuity at phase 0. Nonzero only within one sample of it.
+fn poly_blep(phase: f32, increment: f32) -> f32 {
+    if increment <= 0.0 {
+        0.0
+    } else if phase < increment {
+        let t = phase / increment;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - increment {
+        let t = (phase - 1.0) / increment;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+// Saw with PolyBLEP smoothing its reset, which removes most of the aliasing the naive one has
+// at high pitches. The same as Saw well below Nyquist.
+pub struct BlepSaw<Hz> {
+    hz: Hz,
+    phase: f32,
+    // Cycles per sample, for the width of the correction.
+    increment: f32,
+}
+
+impl<Hz> Source for BlepSaw<Hz>
+    where Hz: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.hz.update(elapsed);
+        self.increment = (self.hz.sample() / elapsed.rate as f32).abs().min(0.5);
+        self.phase = advance_phase(self.phase, self.hz.sample(), elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        2.0 * self.phase - 1.0 - poly_blep(self.phase, self.increment)
+    }
+
+    fn reset(&mut self) {
+        self.hz.reset();
+        self.phase = 0.0;
+        self.increment = 0.0;
+    }
+}
+
+pub fn blep_saw<Hz>(hz: Hz) -> BlepSaw<Hz> {
+    BlepSaw { hz, phase: 0.0, increment: 0.0 }
+}
+
+// 50% square with PolyBLEP on both edges, see BlepSaw.
+pub struct BlepSquare<Hz> {
+    hz: Hz,
+    phase: f32,
+    increment: f32,
+}
+
+impl<Hz> Source for BlepSquare<Hz>
+    where Hz: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.hz.update(elapsed);
+        self.increment = (self.hz.sample() / elapsed.rate as f32).abs().min(0.5);
+        self.phase = advance_phase(self.phase, self.hz.sample(), elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        let naive = if self.phase < 0.5 { 1.0 } else { -1.0 };
+        naive + poly_blep(self.phase, self.increment) - poly_blep((self.phase + 0.5).fract(), self.increment)
+    }
+
+    fn reset(&mut self) {
+        self.hz.reset();
+        self.phase = 0.0;
+        self.increment = 0.0;
+    }
+}
+
+pub fn blep_square<Hz>(hz: Hz) -> BlepSquare<Hz> {
+    BlepSquare { hz, phase: 0.0, increment: 0.0 }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Square,
+    Saw,
+}
+
+impl Waveform {
+    // The same shapes as the oscillators, in -1..=1 for phase in 0..1.
+    pub fn at(self, phase: f32) -> f32 {
+        match self {
+            Self::Sine => (phase * std::f32::consts::TAU).sin(),
+            Self::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Self::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Self::Saw => 2.0 * phase - 1.0,
+        }
+    }
+}
+
+// Low frequency oscillator for modulating any parameter that takes a Source, scaled into
+// min..=max.
+pub struct Lfo<Hz> {
+    hz: Hz,
+    waveform: Waveform,
+    min: f32,
+    max: f32,
+    phase: f32,
+    start_phase: f32,
+}
+
+impl<Hz> Lfo<Hz> {
+    pub fn with_range(self, min: f32, max: f32) -> Self {
+        Self { min, max, ..self }
+    }
+
+    pub fn unipolar(self) -> Self {
+        self.with_range(0.0, 1.0)
+    }
+
+    pub fn with_phase(self, phase: f32) -> Self {
+        Self { phase, start_phase: phase, ..self }
+    }
+}
+
+impl<Hz> Source for Lfo<Hz>
+    where Hz: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.hz.update(elapsed);
+        self.phase = advance_phase(self.phase, self.hz.sample(), elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        let unit = (self.waveform.at(self.phase) + 1.0) / 2.0;
+        self.min + unit * (self.max - self.min)
+    }
+
+    fn reset(&mut self) {
+        self.hz.reset();
+        self.phase = self.start_phase;
+    }
+}
+
+pub fn lfo<Hz>(hz: Hz, waveform: Waveform) -> Lfo<Hz> {
+    Lfo { hz, waveform, min: -1.0, max: 1.0, phase: 0.0, start_phase: 0.0 }
+}
+
+pub enum ADSRState {
+    Before,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    After,
+}
+
+// Shape of each envelope ramp. Stage durations are the same for every curve, only the path
+// between the levels changes.
+#[derive(Copy, Clone, Debug, Default)]
+pub enum Curve {
+    #[default]
+    Linear,
+    // Fast at first, easing into the target like an RC circuit charging.
+    Exponential,
+    // Slow at first, speeding up into the target.
+    Logarithmic,
+}
+
+impl Curve {
+    // Maps progress through a stage, 0..=1, to the fraction of the way to its target level.
+    pub fn shape(self, progress: f32) -> f32 {
+        const STEEPNESS: f32 = 5.0;
+        match self {
+            Self::Linear => progress,
+            Self::Exponential => (1.0 - (-STEEPNESS * progress).exp()) / (1.0 - (-STEEPNESS).exp()),
+            Self::Logarithmic => ((STEEPNESS * progress).exp() - 1.0) / (STEEPNESS.exp() - 1.0),
+        }
+    }
+}
+
+pub struct ADSR {
+    // f64 like time, so comparing against it stays exact for long running notes.
+    active: std::ops::Range<f64>,
+    // The range given on construction, since gating moves active.
+    scheduled: std::ops::Range<f64>,
+    attack_rate: f32,
+    decay_rate: f32,
+    sustain_level: f32,
+    release_rate: f32,
+    curve: Curve,
+
+    // f64, since adding each sample's duration to an f32 stalls after a few minutes.
+    time: f64,
+    state: ADSRState,
+    level: f32,
+    // Level when the current stage started, and how far through it we are.
+    stage_level: f32,
+    stage_progress: f32,
+}
+
+impl Source for ADSR {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        // Check the range against the start of this sample, so a note starting at 0.0 is
+        // already ramping on the first sample, and releases line up the same way.
+        let start = self.time;
+        self.time += elapsed.as_secs_f64();
+        let elapsed = elapsed.as_secs();
+        match self.state {
+            ADSRState::Before if self.active.contains(&start) => {
+                self.enter(ADSRState::Attack);
+            }
+            ADSRState::Sustain if !self.active.contains(&start) => {
+                self.enter(ADSRState::Release);
+            }
+            _ => {}
+        }
+        match self.state {
+            ADSRState::Attack => {
+                if self.ramp(1.0, self.attack_rate, elapsed) {
+                    self.enter(ADSRState::Decay);
+                }
+            }
+            ADSRState::Decay => {
+                if self.ramp(self.sustain_level, self.decay_rate, elapsed) {
+                    self.enter(ADSRState::Sustain);
+                }
+            }
+            ADSRState::Release => {
+                if self.ramp(0.0, self.release_rate, elapsed) {
+                    self.enter(ADSRState::After);
+                }
+            }
+            ADSRState::Before | ADSRState::Sustain | ADSRState::After => {}
+        }
+    }
+
+    fn sample(&self) -> Self::Sample {
+        self.level
+    }
+
+    // Gated envelopes have no end until gate_off().
+    fn length(&self) -> Option<SampleTime> {
+        let time = self.time;
+        let release_secs = |level: f32| f64::from(level / self.release_rate);
+        let end = match self.state {
+            ADSRState::After => time,
+            ADSRState::Release => time + release_secs((1.0 - self.stage_progress) * self.stage_level),
+            // A scheduled release waits for the decay to reach the sustain level.
+            _ => {
+                let ramp_secs = 1.0 / self.attack_rate + (1.0 - self.sustain_level).abs() / self.decay_rate;
+                let decay_end = self.active.start + f64::from(ramp_secs);
+                self.active.end.max(decay_end) + release_secs(self.sustain_level)
+            }
+        };
+        end.is_finite().then(|| SampleTime::from_secs_f64(end))
+    }
+
+    fn reset(&mut self) {
+        self.active = self.scheduled.clone();
+        self.time = 0.0;
+        self.state = ADSRState::Before;
+        self.level = 0.0;
+        self.stage_level = 0.0;
+        self.stage_progress = 0.0;
+    }
+
+    // Until gate_on() restarts it, for gated envelopes.
+    fn finished(&self) -> bool {
+        matches!(self.state, ADSRState::After)
+    }
+}
+
+impl ADSR {
+    // Stage slopes in level per second, rather than adsr()'s times.
+    pub fn from_rates(active: std::ops::Range<f32>, attack_rate: f32, decay_rate: f32, sustain_level: f32, release_rate: f32) -> Self {
+        let active = f64::from(active.start)..f64::from(active.end);
+        Self {
+            active: active.clone(),
+            scheduled: active,
+            attack_rate,
+            decay_rate,
+            sustain_level,
+            release_rate,
+            curve: Curve::Linear,
+            time: 0.0,
+            state: ADSRState::Before,
+            level: 0.0,
+            stage_level: 0.0,
+            stage_progress: 0.0,
+        }
+    }
+
+    pub fn builder() -> AdsrBuilder {
+        AdsrBuilder::default()
+    }
+
+    pub fn with_curve(self, curve: Curve) -> Self {
+        Self { curve, ..self }
+    }
+
+    fn enter(&mut self, state: ADSRState) {
+        self.state = state;
+        self.stage_level = self.level;
+        self.stage_progress = 0.0;
+    }
+
+    // Moves level towards target, with rate in level per second as if the curve was linear.
+    // Returns true once the target is reached.
+    fn ramp(&mut self, target: f32, rate: f32, elapsed: f32) -> bool {
+        let distance = target - self.stage_level;
+        if distance == 0.0 {
+            self.stage_progress = 1.0;
+        } else {
+            self.stage_progress += rate * elapsed / distance.abs();
+        }
+        if self.stage_progress >= 1.0 {
+            self.level = target;
+            true
+        } else {
+            self.level = self.stage_level + distance * self.curve.shape(self.stage_progress);
+            false
+        }
+    }
+
+    // Starts the attack from the current level, so retriggering doesn't click.
+    pub fn gate_on(&mut self) {
+        self.active = self.time..f64::INFINITY;
+        self.enter(ADSRState::Attack);
+    }
+
+    // Releases immediately, even mid attack or decay.
+    pub fn gate_off(&mut self) {
+        self.active.end = self.time;
+        if let ADSRState::Attack | ADSRState::Decay | ADSRState::Sustain = self.state {
+            self.enter(ADSRState::Release);
+        }
+    }
+}
+
+// Stage times in seconds: attack from silence to full level, decay from full to the sustain
+// level, and release from that to silence. A zero time steps instantly.
+pub fn adsr(active: std::ops::Range<f32>, attack_secs: f32, decay_secs: f32, sustain_level: f32, release_secs: f32) -> ADSR {
+    // Releasing early, before reaching a zero sustain level, still has to move, so a stage
+    // with no distance to cover gets the rate for a full level instead.
+    fn rate(distance: f32, secs: f32) -> f32 {
+        if distance > 0.0 { distance / secs } else { 1.0 / secs }
+    }
+
+    ADSR::from_rates(
+        active,
+        rate(1.0, attack_secs),
+        rate(1.0 - sustain_level, decay_secs),
+        sustain_level,
+        rate(sustain_level, release_secs),
+    )
+}
+
+// An envelope that stays silent until gate_on(), for when release times aren't known ahead.
+pub fn adsr_gated(attack_secs: f32, decay_secs: f32, sustain_level: f32, release_secs: f32) -> ADSR {
+    adsr(f32::INFINITY..f32::INFINITY, attack_secs, decay_secs, sustain_level, release_secs)
+}
+
+#[derive(Debug)]
+pub enum AdsrError {
+    InvalidSustain(f32),
+    InvalidTime(f32),
+}
+
+impl std::fmt::Display for AdsrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidSustain(level) => write!(f, "sustain level {level} out of range 0..=1"),
+            Self::InvalidTime(secs) => write!(f, "invalid stage time {secs}s"),
+        }
+    }
+}
+
+impl std::error::Error for AdsrError {}
+
+// Named stage times in seconds, instead of adsr()'s positional ones.
+#[derive(Default)]
+pub struct AdsrBuilder {
+    active: Option<std::ops::Range<f32>>,
+    attack_secs: Option<f32>,
+    decay_secs: Option<f32>,
+    sustain_level: Option<f32>,
+    release_secs: Option<f32>,
+    curve: Curve,
+}
+
+impl AdsrBuilder {
+    // Without this, the envelope is gated, see adsr_gated().
+    pub fn active(mut self, active: std::ops::Range<f32>) -> Self {
+        self.active = Some(active);
+        self
+    }
+
+    pub fn attack(mut self, secs: f32) -> Self {
+        self.attack_secs = Some(secs);
+        self
+    }
+
+    // Time to fall from full level to the sustain level.
+    pub fn decay(mut self, secs: f32) -> Self {
+        self.decay_secs = Some(secs);
+        self
+    }
+
+    pub fn sustain(mut self, level: f32) -> Self {
+        self.sustain_level = Some(level);
+        self
+    }
+
+    // Time to fall from the sustain level to silence.
+    pub fn release(mut self, secs: f32) -> Self {
+        self.release_secs = Some(secs);
+        self
+    }
+
+    pub fn curve(mut self, curve: Curve) -> Self {
+        self.curve = curve;
+        self
+    }
+
+    pub fn build(self) -> Result<ADSR, AdsrError> {
+        let sustain_level = self.sustain_level.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&sustain_level) {
+            return Err(AdsrError::InvalidSustain(sustain_level));
+        }
+        let attack_secs = self.attack_secs.unwrap_or(0.01);
+        let decay_secs = self.decay_secs.unwrap_or(0.1);
+        let release_secs = self.release_secs.unwrap_or(0.1);
+        if let Some(secs) = [attack_secs, decay_secs, release_secs].into_iter().find(|secs| secs.is_nan() || *secs < 0.0) {
+            return Err(AdsrError::InvalidTime(secs));
+        }
+        let envelope = adsr(
+            self.active.unwrap_or(f32::INFINITY..f32::INFINITY),
+            attack_secs,
+            decay_secs,
+            sustain_level,
+            release_secs,
+        );
+        Ok(envelope.with_curve(self.curve))
+    }
+}
+
+pub struct Add<L, R> {
+    left: L,
+    right: R,
+}
+
+impl<L, R> Source for Add<L, R> where L: Source, R: Source, L::Sample: std::ops::Add<R::Sample> {
+    type Sample = <L::Sample as std::ops::Add<R::Sample>>::Output;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.left.update(elapsed);
+        self.right.update(elapsed);
+    }
+
+    fn sample(&self) -> Self::Sample {
+        self.left.sample() + self.right.sample()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        max_length(self.left.length(), self.right.length())
+    }
+
+    fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+    }
+
+    fn finished(&self) -> bool {
+        self.left.finished() && self.right.finished()
+    }
+}
+
+pub struct Mul<L, R> {
+    left: L,
+    right: R,
+}
+
+impl<L, R> Source for Mul<L, R>
+    where L: Source,
+          R: Source,
+          L::Sample: std::ops::Mul<R::Sample>
+{
+    type Sample = <L::Sample as std::ops::Mul<R::Sample>>::Output;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.left.update(elapsed);
+        self.right.update(elapsed);
+    }
+
+    fn sample(&self) -> Self::Sample {
+        self.left.sample() * self.right.sample()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        // Silent once either side is, so an oscillator times an envelope ends with the envelope.
+        min_length(self.left.length(), self.right.length())
+    }
+
+    fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+    }
+
+    // Like length, silent once either side is.
+    fn finished(&self) -> bool {
+        self.left.finished() || self.right.finished()
+    }
+}
+
+pub struct Sub<L, R> {
+    left: L,
+    right: R,
+}
+
+impl<L, R> Source for Sub<L, R>
+    where L: Source,
+          R: Source,
+          L::Sample: std::ops::Sub<R::Sample>
+{
+    type Sample = <L::Sample as std::ops::Sub<R::Sample>>::Output;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.left.update(elapsed);
+        self.right.update(elapsed);
+    }
+
+    fn sample(&self) -> Self::Sample {
+        self.left.sample() - self.right.sample()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        max_length(self.left.length(), self.right.length())
+    }
+
+    fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+    }
+
+    fn finished(&self) -> bool {
+        self.left.finished() && self.right.finished()
+    }
+}
+
+pub struct Neg<T> {
+    source: T,
+}
+
+impl<T> Source for Neg<T>
+    where T: Source,
+          T::Sample: std::ops::Neg
+{
+    type Sample = <T::Sample as std::ops::Neg>::Output;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+    }
+
+    fn sample(&self) -> Self::Sample {
+        -self.source.sample()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+    }
+
+    fn finished(&self) -> bool {
+        self.source.finished()
+    }
+}
+
+impl<L, R> std::ops::Add<R> for Wrapped<L> {
+    type Output = Wrapped<Add<L, R>>;
+
+    fn add(self, rhs: R) -> Self::Output {
+        Wrapped(Add { left: self.0, right: rhs })
+    }
+}
+
+impl<L, R> std::ops::Mul<R> for Wrapped<L> {
+    type Output = Wrapped<Mul<L, R>>;
+
+    fn mul(self, rhs: R) -> Self::Output {
+        Wrapped(Mul { left: self.0, right: rhs })
+    }
+}
+
+// So scaling reads the same either way around, `0.5 * source.wrap()`.
+impl<R> std::ops::Mul<Wrapped<R>> for f32 {
+    type Output = Wrapped<Mul<f32, R>>;
+
+    fn mul(self, rhs: Wrapped<R>) -> Self::Output {
+        Wrapped(Mul { left: self, right: rhs.0 })
+    }
+}
+
+impl<L, R> std::ops::Sub<R> for Wrapped<L> {
+    type Output = Wrapped<Sub<L, R>>;
+
+    fn sub(self, rhs: R) -> Self::Output {
+        Wrapped(Sub { left: self.0, right: rhs })
+    }
+}
+
+impl<T> std::ops::Neg for Wrapped<T> {
+    type Output = Wrapped<Neg<T>>;
+
+    fn neg(self) -> Self::Output {
+        Wrapped(Neg { source: self.0 })
+    }
+}
+
+// Direct form I biquad, coefficients normalized so a0 == 1.
+// See the RBJ "Audio EQ Cookbook" for the coefficient formulas.
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    fn new() -> Self {
+        // Passthrough until the first coefficient update.
+        Self { b0: 1.0, b1: 0.0, b2: 0.0, a1: 0.0, a2: 0.0, x1: 0.0, x2: 0.0, y1: 0.0, y2: 0.0 }
+    }
+
+    // Returns (cos(w0), alpha) for the cookbook formulas, keeping hz below Nyquist.
+    fn prewarp(hz: f32, q: f32, rate: u32) -> (f32, f32) {
+        let rate = rate as f32;
+        let hz = hz.clamp(1.0, rate * 0.49);
+        let w0 = std::f32::consts::TAU * hz / rate;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    fn set(&mut self, b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32) {
+        self.b0 = b0 / a0;
+        self.b1 = b1 / a0;
+        self.b2 = b2 / a0;
+        self.a1 = a1 / a0;
+        self.a2 = a2 / a0;
+    }
+
+    fn set_low_pass(&mut self, hz: f32, q: f32, rate: u32) {
+        let (cos, alpha) = Self::prewarp(hz, q, rate);
+        self.set(
+            (1.0 - cos) / 2.0,
+            1.0 - cos,
+            (1.0 - cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        );
+    }
+
+    fn set_high_pass(&mut self, hz: f32, q: f32, rate: u32) {
+        let (cos, alpha) = Self::prewarp(hz, q, rate);
+        self.set(
+            (1.0 + cos) / 2.0,
+            -(1.0 + cos),
+            (1.0 + cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        );
+    }
+
+    // Constant 0dB peak gain at the center frequency.
+    fn set_band_pass(&mut self, hz: f32, q: f32, rate: u32) {
+        let (cos, alpha) = Self::prewarp(hz, q, rate);
+        self.set(
+            alpha,
+            0.0,
+            -alpha,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        );
+    }
+
+    // Boosts or cuts by gain_db around hz, with Q setting the width.
+    fn set_peak(&mut self, hz: f32, gain_db: f32, q: f32, rate: u32) {
+        let (cos, alpha) = Self::prewarp(hz, q, rate);
+        let a = 10f32.powf(gain_db / 40.0);
+        self.set(
+            1.0 + alpha * a,
+            -2.0 * cos,
+            1.0 - alpha * a,
+            1.0 + alpha / a,
+            -2.0 * cos,
+            1.0 - alpha / a,
+        );
+    }
+
+    // Boosts or cuts by gain_db below hz.
+    fn set_low_shelf(&mut self, hz: f32, gain_db: f32, q: f32, rate: u32) {
+        let (cos, alpha) = Self::prewarp(hz, q, rate);
+        let a = 10f32.powf(gain_db / 40.0);
+        let sqrt_alpha = 2.0 * a.sqrt() * alpha;
+        self.set(
+            a * ((a + 1.0) - (a - 1.0) * cos + sqrt_alpha),
+            2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+            a * ((a + 1.0) - (a - 1.0) * cos - sqrt_alpha),
+            (a + 1.0) + (a - 1.0) * cos + sqrt_alpha,
+            -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+            (a + 1.0) + (a - 1.0) * cos - sqrt_alpha,
+        );
+    }
+
+    // Boosts or cuts by gain_db above hz.
+    fn set_high_shelf(&mut self, hz: f32, gain_db: f32, q: f32, rate: u32) {
+        let (cos, alpha) = Self::prewarp(hz, q, rate);
+        let a = 10f32.powf(gain_db / 40.0);
+        let sqrt_alpha = 2.0 * a.sqrt() * alpha;
+        self.set(
+            a * ((a + 1.0) + (a - 1.0) * cos + sqrt_alpha),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+            a * ((a + 1.0) + (a - 1.0) * cos - sqrt_alpha),
+            (a + 1.0) - (a - 1.0) * cos + sqrt_alpha,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos),
+            (a + 1.0) - (a - 1.0) * cos - sqrt_alpha,
+        );
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+
+    fn output(&self) -> f32 {
+        self.y1
+    }
+}
+
+// Lower Q makes alpha blow up as sin(w0) / 2Q, so filters clamp to this.
+pub const MIN_Q: f32 = 0.1;
+
+pub struct LowPass<S, Cutoff> {
+    source: S,
+    cutoff: Cutoff,
+    filter: Biquad,
+}
+
+impl<S, Cutoff> Source for LowPass<S, Cutoff>
+    where S: Source<Sample=f32>,
+          Cutoff: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        self.cutoff.update(elapsed);
+        // Butterworth Q, so there's no resonant peak at the cutoff.
+        self.filter.set_low_pass(self.cutoff.sample(), std::f32::consts::FRAC_1_SQRT_2, elapsed.rate);
+        self.filter.process(self.source.sample());
+    }
+
+    fn sample(&self) -> f32 {
+        self.filter.output()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.cutoff.reset();
+        self.filter = Biquad::new();
+    }
+}
+
+pub struct HighPass<S, Cutoff> {
+    source: S,
+    cutoff: Cutoff,
+    q: f32,
+    filter: Biquad,
+}
+
+impl<S, Cutoff> Source for HighPass<S, Cutoff>
+    where S: Source<Sample=f32>,
+          Cutoff: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        self.cutoff.update(elapsed);
+        self.filter.set_high_pass(self.cutoff.sample(), self.q, elapsed.rate);
+        self.filter.process(self.source.sample());
+    }
+
+    fn sample(&self) -> f32 {
+        self.filter.output()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.cutoff.reset();
+        self.filter = Biquad::new();
+    }
+}
+
+pub struct BandPass<S, Center> {
+    source: S,
+    center: Center,
+    q: f32,
+    filter: Biquad,
+}
+
+impl<S, Center> Source for BandPass<S, Center>
+    where S: Source<Sample=f32>,
+          Center: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        self.center.update(elapsed);
+        self.filter.set_band_pass(self.center.sample(), self.q, elapsed.rate);
+        self.filter.process(self.source.sample());
+    }
+
+    fn sample(&self) -> f32 {
+        self.filter.output()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.center.reset();
+        self.filter = Biquad::new();
+    }
+}
+
+// Anything at or above 1.0 would grow without bound.
+pub const MAX_FEEDBACK: f32 = 0.99;
+
+pub struct Echo<S> {
+    source: S,
+    delay_secs: f32,
+    feedback: f32,
+
+    // The buffer is sized from the rate seen in update, so it's empty until then.
+    rate: u32,
+    buffer: Vec<f32>,
+    pos: usize,
+    output: f32,
+}
+
+impl<S> Source for Echo<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        if self.rate != elapsed.rate {
+            self.rate = elapsed.rate;
+            let len = (self.delay_secs * elapsed.rate as f32).round().max(1.0) as usize;
+            self.buffer = vec![0.0; len];
+            self.pos = 0;
+        }
+        // y[n] = x[n] + feedback * y[n - delay]
+        let delayed = self.buffer[self.pos];
+        self.output = self.source.sample() + self.feedback * delayed;
+        self.buffer[self.pos] = self.output;
+        self.pos = (self.pos + 1) % self.buffer.len();
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        // Reallocates the buffer on the next update.
+        self.rate = 0;
+        self.output = 0.0;
+    }
+}
+
+// Small deterministic PRNG (Marsaglia's xorshift32), so renders are reproducible.
+#[derive(Clone)]
+struct XorShift(u32);
+
+impl XorShift {
+    fn new(seed: u32) -> Self {
+        // An all-zero state would only ever produce zeros.
+        Self(if seed == 0 { 0x9E37_79B9 } else { seed })
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    // In 0..1
+    fn next_unit(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+
+    // In -1..1
+    fn next_bipolar(&mut self) -> f32 {
+        self.next_unit() * 2.0 - 1.0
+    }
+}
+
+pub struct Noise {
+    seed: u32,
+    rng: XorShift,
+    value: f32,
+}
+
+impl Noise {
+    pub fn pink(self) -> Pink {
+        Pink { white: self, b0: 0.0, b1: 0.0, b2: 0.0 }
+    }
+}
+
+impl Source for Noise {
+    type Sample = f32;
+
+    fn update(&mut self, _elapsed: SampleTime) {
+        self.value = self.rng.next_bipolar();
+    }
+
+    fn sample(&self) -> f32 {
+        self.value
+    }
+
+    fn reset(&mut self) {
+        self.rng = XorShift::new(self.seed);
+        self.value = 0.0;
+    }
+}
+
+pub fn noise() -> Noise {
+    noise_seeded(1)
+}
+
+pub fn noise_seeded(seed: u32) -> Noise {
+    Noise { seed, rng: XorShift::new(seed), value: 0.0 }
+}
+
+// Paul Kellet's "economy" pink filter: three one-pole lowpasses summed with the white input
+// approximate -3dB/octave. The poles are tuned for 44.1kHz but hold up fine at 48kHz.
+pub struct Pink {
+    white: Noise,
+    b0: f32,
+    b1: f32,
+    b2: f32,
+}
+
+impl Source for Pink {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.white.update(elapsed);
+        let white = self.white.sample();
+        self.b0 = 0.99765 * self.b0 + white * 0.0990460;
+        self.b1 = 0.96300 * self.b1 + white * 0.2965164;
+        self.b2 = 0.57000 * self.b2 + white * 1.0526913;
+    }
+
+    fn sample(&self) -> f32 {
+        // Scaled back to roughly -1..1.
+        (self.b0 + self.b1 + self.b2 + self.white.sample() * 0.1848) * 0.11
+    }
+
+    fn reset(&mut self) {
+        self.white.reset();
+        self.b0 = 0.0;
+        self.b1 = 0.0;
+        self.b2 = 0.0;
+    }
+}
+
+pub fn pink() -> Pink {
+    noise().pink()
+}
+
+// Constant power pan: position -1 is full left, 1 is full right, and 0 is -3dB on both.
+pub struct Pan<S, Position> {
+    source: S,
+    position: Position,
+}
+
+impl<S, Position> Source for Pan<S, Position>
+    where S: Source<Sample=f32>,
+          Position: Source<Sample=f32>,
+{
+    type Sample = [f32; 2];
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        self.position.update(elapsed);
+    }
+
+    fn sample(&self) -> [f32; 2] {
+        let angle = (self.position.sample().clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+        let value = self.source.sample();
+        [value * angle.cos(), value * angle.sin()]
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.position.reset();
+    }
+}
+
+// Pan is already constant power, so auto panning keeps the same power across the sweep.
+pub type AutoPan<S, Hz> = Pan<S, Lfo<Hz>>;
+
+// Steps the source once per interleaved frame of buf, the same for the live stream and offline.
+pub fn render_interleaved<S>(source: &mut S, buf: &mut [f32], channels: usize, rate: u32)
+    where S: Source,
+          S::Sample: Frame,
+{
+    for frame in buf.chunks_mut(channels.max(1)) {
+        source.update(SampleTime { count: 1, rate });
+        source.sample().write_to(frame);
+    }
+}
+
+// Counts rendered frames rather than reading device timestamps, so render timing can be
+// driven and checked without real time passing.
+pub struct FrameClock {
+    rate: u32,
+    frames: u64,
+}
+
+impl FrameClock {
+    pub fn new(rate: u32) -> Self {
+        Self { rate, frames: 0 }
+    }
+
+    pub fn advance(&mut self, frames: usize) {
+        self.frames += frames as u64;
+    }
+
+    pub fn elapsed(&self) -> std::time::Duration {
+        std::time::Duration::from_nanos((self.frames as u128 * 1_000_000_000 / self.rate as u128) as u64)
+    }
+
+    // Frames left until `duration` has passed. Integer math, so e.g. exactly 1s at 44.1kHz is
+    // exactly 44100 frames.
+    pub fn remaining(&self, duration: std::time::Duration) -> u64 {
+        let total = (duration.as_nanos() * self.rate as u128 / 1_000_000_000) as u64;
+        total.saturating_sub(self.frames)
+    }
+}
+
+// Renders interleaved blocks of up to block_frames to `out` until `duration` has passed on
+// the clock, with a shorter last block if needed to stop exactly on time.
+pub fn render_blocks<S>(source: &mut S, clock: &mut FrameClock, channels: usize, block_frames: usize, duration: std::time::Duration, mut out: impl FnMut(&[f32]))
+    where S: Source,
+          S::Sample: Frame,
+{
+    let mut buf = vec![0.0; block_frames.max(1) * channels.max(1)];
+    loop {
+        let frames = clock.remaining(duration).min(block_frames.max(1) as u64) as usize;
+        if frames == 0 {
+            break;
+        }
+        let block = &mut buf[..frames * channels.max(1)];
+        render_interleaved(source, block, channels, clock.rate);
+        clock.advance(frames);
+        out(block);
+    }
+}
+
+// Renders `frames` mono samples, stepping the source exactly the same as the live stream.
+pub fn render_to_buffer<S>(mut source: S, sample_rate: u32, frames: usize) -> Vec<f32>
+    where S: Source<Sample=f32>,
+{
+    let mut buffer = vec![0.0; frames];
+    source.fill(&mut buffer, sample_rate);
+    buffer
+}
+
+// Headroom diagnostics for a rendered buffer: clipped counts samples beyond -1..=1, which
+// would be clamped on output, and peak is the largest magnitude.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ClipReport {
+    pub peak: f32,
+    pub clipped: usize,
+}
+
+impl ClipReport {
+    pub fn of(buffer: &[f32]) -> Self {
+        buffer.iter().fold(Self::default(), |report, sample| Self {
+            peak: report.peak.max(sample.abs()),
+            clipped: report.clipped + (sample.abs() > 1.0) as usize,
+        })
+    }
+
+    pub fn is_clipping(&self) -> bool {
+        self.clipped > 0
+    }
+}
+
+// render_to_buffer, along with a report of whether the result clips.
+pub fn render_checked<S>(source: S, sample_rate: u32, frames: usize) -> (Vec<f32>, ClipReport)
+    where S: Source<Sample=f32>,
+{
+    let buffer = render_to_buffer(source, sample_rate, frames);
+    let report = ClipReport::of(&buffer);
+    (buffer, report)
+}
+
+// Scales so the loudest sample's magnitude is target_peak, returning the gain used. The
+// same gain applies throughout, so relative dynamics are kept. Silence is left alone.
+pub fn normalize(buffer: &mut [f32], target_peak: f32) -> f32 {
+    let peak = buffer.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+    scale_to(buffer, peak, target_peak)
+}
+
+// Like normalize, but to a target RMS level, which follows loudness better than the peak.
+// This can push peaks past 1.
+pub fn normalize_rms(buffer: &mut [f32], target_rms: f32) -> f32 {
+    let sum: f64 = buffer.iter().map(|&sample| sample as f64 * sample as f64).sum();
+    let rms = (sum / buffer.len().max(1) as f64).sqrt() as f32;
+    scale_to(buffer, rms, target_rms)
+}
+
+fn scale_to(buffer: &mut [f32], level: f32, target: f32) -> f32 {
+    if level == 0.0 || !level.is_finite() {
+        return 1.0;
+    }
+    let gain = target / level;
+    for sample in buffer.iter_mut() {
+        *sample *= gain;
+    }
+    gain
+}
+
+// Separate sources for the left and right channels, e.g. for write_wav.
+pub struct Stereo<L, R> {
+    left: L,
+    right: R,
+}
+
+impl<L, R> Source for Stereo<L, R>
+    where L: Source<Sample=f32>,
+          R: Source<Sample=f32>,
+{
+    type Sample = [f32; 2];
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.left.update(elapsed);
+        self.right.update(elapsed);
+    }
+
+    fn sample(&self) -> [f32; 2] {
+        [self.left.sample(), self.right.sample()]
+    }
+
+    fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+    }
+
+    fn finished(&self) -> bool {
+        self.left.finished() && self.right.finished()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        max_length(self.left.length(), self.right.length())
+    }
+}
+
+pub fn stereo<L, R>(left: L, right: R) -> Stereo<L, R> {
+    Stereo { left, right }
+}
+
+// One source per channel, in channel order. The sources have to be the same type, so box
+// them to mix kinds, or use stereo() for two.
+impl<S, const N: usize> Source for [S; N]
+    where S: Source<Sample=f32>,
+{
+    type Sample = [f32; N];
+
+    fn update(&mut self, elapsed: SampleTime) {
+        for source in self {
+            source.update(elapsed);
+        }
+    }
+
+    fn sample(&self) -> [f32; N] {
+        std::array::from_fn(|channel| self[channel].sample())
+    }
+
+    fn reset(&mut self) {
+        for source in self {
+            source.reset();
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.iter().all(|source| source.finished())
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.iter().try_fold(SampleTime { count: 0, rate: 1 }, |length, source| max_length(Some(length), source.length()))
+    }
+}
+
+// Renders `duration` of `source` to a 16-bit PCM WAV file, without opening an audio device.
+// The file has a channel per channel of the source's frames, interleaved in channel order, so
+// for stereo, left then right.
+pub fn render_wav<S>(source: S, sample_rate: u32, duration: std::time::Duration, path: impl AsRef<std::path::Path>) -> std::io::Result<()>
+    where S: Source,
+          S::Sample: Frame,
+{
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write_wav(file, source, sample_rate, duration)
+}
+
+pub fn write_wav<S>(mut out: impl std::io::Write, mut source: S, sample_rate: u32, duration: std::time::Duration) -> std::io::Result<()>
+    where S: Source,
+          S::Sample: Frame,
+{
+    // Checked before writing anything, since the RIFF sizes are 32 bits and the total after
+    // the first 8 bytes of the file has to fit.
+    let too_long = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "too long for a WAV file");
+    let channels = u16::try_from(S::Sample::CHANNELS)
+        .ok()
+        .filter(|&channels| channels > 0)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "unsupported channel count for a WAV file"))?;
+    let frames = FrameClock::new(sample_rate).remaining(duration);
+    let block_align = channels as u32 * 2;
+    let data_len = frames.checked_mul(block_align as u64)
+        .and_then(|len| u32::try_from(len).ok())
+        .filter(|len| len.checked_add(36).is_some())
+        .ok_or_else(too_long)?;
+    let byte_rate = sample_rate.checked_mul(block_align)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "sample rate too high for a WAV file"))?;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&channels.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&byte_rate.to_le_bytes())?;
+    out.write_all(&(block_align as u16).to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+
+    let mut result = Ok(());
+    render_blocks(&mut source, &mut FrameClock::new(sample_rate), channels as usize, 1024, duration, |block| {
+        for value in block {
+            let value = (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            if result.is_ok() {
+                result = out.write_all(&value.to_le_bytes());
+            }
+        }
+    });
+    result?;
+    out.flush()
+}
+
+// Hard limits each sample to min..=max.
+pub struct Clamp<S> {
+    source: S,
+    min: f32,
+    max: f32,
+}
+
+impl<S> Source for Clamp<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        self.source.sample().clamp(self.min, self.max)
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+    }
+}
+
+// Soft limits to -1..1 with tanh, which is close to linear for quiet signals.
+pub struct Saturate<S> {
+    source: S,
+}
+
+impl<S> Source for Saturate<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        self.source.sample().tanh()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+    }
+}
+
+// Sums a runtime-variable set of voices, where a static Add tree can't be used.
+pub struct Mixer {
+    voices: Vec<Box<dyn Source<Sample=f32> + Send>>,
+}
+
+impl Mixer {
+    pub fn add_voice(&mut self, voice: impl Source<Sample=f32> + Send + 'static) {
+        self.voices.push(Box::new(voice));
+    }
+
+    pub fn remove_voice(&mut self, index: usize) -> Box<dyn Source<Sample=f32> + Send> {
+        self.voices.remove(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.voices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.voices.is_empty()
+    }
+
+    pub fn remove_finished(&mut self) {
+        self.voices.retain(|voice| !voice.finished());
+    }
+}
+
+impl Source for Mixer {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        for voice in &mut self.voices {
+            voice.update(elapsed);
+        }
+    }
+
+    // Silent with no voices.
+    fn sample(&self) -> f32 {
+        self.voices.iter().map(|voice| voice.sample()).sum()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        // An empty mixer is already silent, though voices can still be added.
+        self.voices.iter().try_fold(SampleTime { count: 0, rate: 1 }, |length, voice| max_length(Some(length), voice.length()))
+    }
+
+    fn reset(&mut self) {
+        for voice in &mut self.voices {
+            voice.reset();
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.voices.iter().all(|voice| voice.finished())
+    }
+}
+
+pub fn mixer() -> Mixer {
+    Mixer { voices: Vec::new() }
+}
+
+struct Voice {
+    note: Note,
+    // Order of note_on, to find the oldest voice to steal.
+    age: u64,
+    source: Mul<Sine<f32>, ADSR>,
+    // From the note's velocity, after the synth's velocity curve.
+    gain: f32,
+    // Let go of while the sustain pedal was down, so it releases with the pedal.
+    pending_release: bool,
+}
+
+impl Voice {
+    fn envelope(&self) -> &ADSR {
+        &self.source.right
+    }
+
+    fn is_held(&self) -> bool {
+        self.envelope().active.end == f64::INFINITY
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self.envelope().state, ADSRState::After)
+    }
+
+    fn release(&mut self) {
+        self.source.right.gate_off();
+    }
+}
+
+// Polyphonic instrument with a fixed pool of sine voices, stealing the oldest when full.
+pub struct Synth {
+    max_voices: usize,
+    voices: Vec<Voice>,
+    next_age: u64,
+
+    attack_secs: f32,
+    decay_secs: f32,
+    sustain_level: f32,
+    release_secs: f32,
+    velocity_curve: Curve,
+    sustain: bool,
+}
+
+impl Synth {
+    // Maps note velocity to gain. Logarithmic keeps soft notes quieter, Exponential louder.
+    pub fn with_velocity_curve(mut self, curve: Curve) -> Self {
+        self.velocity_curve = curve;
+        self
+    }
+
+    // Full velocity.
+    pub fn note_on(&mut self, note: Note) {
+        self.note_on_velocity(note, 1.0);
+    }
+
+    // Velocity is 0..=1, where 0 is silent.
+    pub fn note_on_velocity(&mut self, note: Note, velocity: f32) {
+        self.voices.retain(|voice| !voice.is_finished());
+        if self.voices.len() >= self.max_voices {
+            if let Some(oldest) = (0..self.voices.len()).min_by_key(|&index| self.voices[index].age) {
+                self.voices.swap_remove(oldest);
+            }
+        }
+        if self.max_voices == 0 {
+            return;
+        }
+        let mut envelope = adsr_gated(
+            self.attack_secs,
+            self.decay_secs,
+            self.sustain_level,
+            self.release_secs,
+        );
+        envelope.gate_on();
+        self.voices.push(Voice {
+            note,
+            age: self.next_age,
+            source: Mul { left: note.sine(), right: envelope },
+            gain: self.velocity_curve.shape(velocity.clamp(0.0, 1.0)),
+            pending_release: false,
+        });
+        self.next_age += 1;
+    }
+
+    // Releases the oldest voice holding the note, so a note started twice, e.g. by
+    // overlapping sequence events, needs two note_offs. Does nothing if the note isn't held.
+    // With the sustain pedal down, the release waits for the pedal to come up.
+    pub fn note_off(&mut self, note: Note) {
+        let oldest = self.voices.iter_mut()
+            .filter(|voice| voice.note == note && voice.is_held() && !voice.pending_release)
+            .min_by_key(|voice| voice.age);
+        if let Some(voice) = oldest {
+            if self.sustain {
+                voice.pending_release = true;
+            } else {
+                voice.release();
+            }
+        }
+    }
+
+    // Letting the pedal up releases every note let go of while it was down, at once. Notes
+    // still held, including ones pressed again while sustained, carry on until their note_off.
+    pub fn set_sustain(&mut self, down: bool) {
+        self.sustain = down;
+        if down {
+            return;
+        }
+        for voice in &mut self.voices {
+            if voice.pending_release {
+                voice.pending_release = false;
+                voice.release();
+            }
+        }
+    }
+
+    pub fn play_event(&mut self, event: MidiEvent) {
+        match event {
+            MidiEvent::NoteOn { note, velocity } => self.note_on_velocity(note, velocity as f32 / 127.0),
+            MidiEvent::NoteOff(note) => self.note_off(note),
+            MidiEvent::Sustain(down) => self.set_sustain(down),
+        }
+    }
+}
+
+impl Source for Synth {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        for voice in &mut self.voices {
+            voice.source.update(elapsed);
+        }
+    }
+
+    fn sample(&self) -> f32 {
+        self.voices.iter().map(|voice| voice.source.sample() * voice.gain).sum()
+    }
+
+    fn reset(&mut self) {
+        self.voices.clear();
+        self.next_age = 0;
+        self.sustain = false;
+    }
+}
+
+pub fn synth(max_voices: usize, attack_secs: f32, decay_secs: f32, sustain_level: f32, release_secs: f32) -> Synth {
+    Synth {
+        max_voices,
+        voices: Vec::with_capacity(max_voices),
+        next_age: 0,
+        attack_secs,
+        decay_secs,
+        sustain_level,
+        release_secs,
+        velocity_curve: Curve::Linear,
+        sustain: false,
+    }
+}
+
+pub struct SequenceEvent {
+    start_beat: f32,
+    duration_beats: f32,
+    note: Note,
+}
+
+// Plays notes at musical times through a Synth, so overlapping notes get their own voices.
+pub struct Sequence {
+    bpm: f32,
+    // Sorted by start_beat.
+    events: Vec<SequenceEvent>,
+    next_event: usize,
+    // End beats of notes that have started.
+    playing: Vec<(f32, Note)>,
+    synth: Synth,
+    // Counted in samples rather than accumulating seconds, so timing doesn't drift.
+    samples: u64,
+}
+
+impl Sequence {
+    pub fn note(mut self, start_beat: f32, duration_beats: f32, note: Note) -> Self {
+        let index = self.events.partition_point(|event| event.start_beat <= start_beat);
+        self.events.insert(index, SequenceEvent { start_beat, duration_beats, note });
+        self
+    }
+
+    // Changes key without rewriting every note.
+    pub fn transpose(mut self, semitones: i32) -> Self {
+        for event in &mut self.events {
+            event.note = event.note.transpose(semitones);
+        }
+        self
+    }
+
+    pub fn beats_at(&self, rate: u32) -> f32 {
+        (self.samples as f64 / rate as f64 * self.bpm as f64 / 60.0) as f32
+    }
+}
+
+impl Source for Sequence {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        // Events trigger at the start of the sample they fall in, like ADSR ranges.
+        let beat = self.beats_at(elapsed.rate);
+        let synth = &mut self.synth;
+        self.playing.retain(|&(end_beat, note)| {
+            let ended = end_beat <= beat;
+            if ended {
+                synth.note_off(note);
+            }
+            !ended
+        });
+        while let Some(event) = self.events.get(self.next_event) {
+            if event.start_beat > beat {
+                break;
+            }
+            self.synth.note_on(event.note);
+            self.playing.push((event.start_beat + event.duration_beats, event.note));
+            self.next_event += 1;
+        }
+        self.synth.update(elapsed);
+        self.samples += elapsed.count;
+    }
+
+    fn sample(&self) -> f32 {
+        self.synth.sample()
+    }
+
+    fn reset(&mut self) {
+        self.next_event = 0;
+        self.playing.clear();
+        self.synth.reset();
+        self.samples = 0;
+    }
+}
+
+pub fn sequence(bpm: f32, synth: Synth) -> Sequence {
+    Sequence {
+        bpm,
+        events: Vec::new(),
+        next_event: 0,
+        playing: Vec::new(),
+        synth,
+        samples: 0,
+    }
+}
+
+// One-pole highpass removing DC offset: y[n] = x[n] - x[n-1] + R * y[n-1]
+pub struct DcBlock<S> {
+    source: S,
+    // None until the first sample, which is then treated as the previous input too, so an
+    // offset already present at the start doesn't cause a step.
+    x1: Option<f32>,
+    y1: f32,
+}
+
+impl<S> Source for DcBlock<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        // Cutoff around 35Hz at 44.1kHz.
+        const R: f32 = 0.995;
+        self.source.update(elapsed);
+        let x = self.source.sample();
+        let x1 = self.x1.unwrap_or(x);
+        self.y1 = x - x1 + R * self.y1;
+        self.x1 = Some(x);
+    }
+
+    fn sample(&self) -> f32 {
+        self.y1
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.x1 = None;
+        self.y1 = 0.0;
+    }
+}
+
+// Raised cosine gain ramps at the edges of a source, to avoid clicks when it starts or stops.
+pub struct Fade<S> {
+    source: S,
+    fade_in: f32,
+    fade_out_start: f32,
+    fade_out: f32,
+
+    samples: u64,
+    gain: f32,
+}
+
+impl<S> Source for Fade<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        fn ramp(progress: f32) -> f32 {
+            0.5 - 0.5 * (progress.clamp(0.0, 1.0) * std::f32::consts::PI).cos()
+        }
+
+        self.source.update(elapsed);
+        // Time at the start of this sample, so a fade in starts from silence.
+        let time = (self.samples as f64 / elapsed.rate as f64) as f32;
+        self.samples += elapsed.count;
+        let fade_in = if self.fade_in > 0.0 { ramp(time / self.fade_in) } else { 1.0 };
+        let fade_out = if time < self.fade_out_start {
+            1.0
+        } else if self.fade_out > 0.0 {
+            1.0 - ramp((time - self.fade_out_start) / self.fade_out)
+        } else {
+            0.0
+        };
+        self.gain = fade_in * fade_out;
+    }
+
+    fn sample(&self) -> f32 {
+        self.gain * self.source.sample()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        let fade_out = (self.fade_out_start + self.fade_out).is_finite().then(|| SampleTime::from_secs(self.fade_out_start + self.fade_out));
+        min_length(self.source.length(), fade_out)
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.samples = 0;
+        self.gain = 0.0;
+    }
+}
+
+pub fn fade<S>(source: S, fade_in: f32, fade_out_start: f32, fade_out: f32) -> Fade<S> {
+    Fade { source, fade_in, fade_out_start, fade_out, samples: 0, gain: 0.0 }
+}
+
+// Plays back a recorded buffer, resampling from its native rate with linear interpolation.
+pub struct SamplePlayer {
+    buffer: Vec<f32>,
+    native_rate: u32,
+    looping: bool,
+
+    // In buffer samples: the current read position, and the one for the next update.
+    position: f64,
+    next_position: f64,
+}
+
+impl SamplePlayer {
+    pub fn looping(self) -> Self {
+        Self { looping: true, ..self }
+    }
+}
+
+impl Source for SamplePlayer {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        let len = self.buffer.len() as f64;
+        self.position = self.next_position;
+        self.next_position += elapsed.count as f64 * self.native_rate as f64 / elapsed.rate as f64;
+        if self.looping && len > 0.0 {
+            self.next_position %= len;
+        }
+    }
+
+    // Silent once a non-looping buffer has played through.
+    fn sample(&self) -> f32 {
+        let index = self.position as usize;
+        let Some(&current) = self.buffer.get(index) else {
+            return 0.0;
+        };
+        let next = match self.buffer.get(index + 1) {
+            Some(&next) => next,
+            None if self.looping => self.buffer[0],
+            None => current,
+        };
+        let fract = self.position.fract() as f32;
+        current + (next - current) * fract
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        if self.looping {
+            None
+        } else {
+            Some(SampleTime { count: self.buffer.len() as u64, rate: self.native_rate })
+        }
+    }
+
+    fn reset(&mut self) {
+        self.position = 0.0;
+        self.next_position = 0.0;
+    }
+
+    fn finished(&self) -> bool {
+        !self.looping && self.next_position >= self.buffer.len() as f64
+    }
+}
+
+pub fn play_sample(buffer: Vec<f32>, native_rate: u32) -> SamplePlayer {
+    SamplePlayer { buffer, native_rate, looping: false, position: 0.0, next_position: 0.0 }
+}
+
+// Portamento: a frequency that ramps over glide_secs to each new target, for use as the Hz of
+// an oscillator.
+pub struct Glide {
+    glide_secs: f32,
+    // Ramp evenly in pitch rather than in Hz, so the glide sounds even across the range.
+    exponential: bool,
+    start_hz: f32,
+
+    from: f32,
+    target: f32,
+    progress: f32,
+    value: f32,
+}
+
+impl Glide {
+    pub fn exponential(self) -> Self {
+        Self { exponential: true, ..self }
+    }
+
+    // Starts a new ramp from wherever the current one has got to.
+    pub fn set_target(&mut self, hz: f32) {
+        self.from = self.value;
+        self.target = hz;
+        self.progress = 0.0;
+    }
+}
+
+impl Source for Glide {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        if self.glide_secs > 0.0 {
+            self.progress = (self.progress + elapsed.as_secs() / self.glide_secs).min(1.0);
+        } else {
+            self.progress = 1.0;
+        }
+        self.value = if self.progress >= 1.0 {
+            self.target
+        } else if self.exponential && self.from > 0.0 && self.target > 0.0 {
+            self.from * (self.target / self.from).powf(self.progress)
+        } else {
+            self.from + (self.target - self.from) * self.progress
+        };
+    }
+
+    fn sample(&self) -> f32 {
+        self.value
+    }
+
+    fn reset(&mut self) {
+        self.from = self.start_hz;
+        self.target = self.start_hz;
+        self.progress = 1.0;
+        self.value = self.start_hz;
+    }
+}
+
+pub fn glide(hz: f32, glide_secs: f32) -> Glide {
+    Glide { glide_secs, exponential: false, start_hz: hz, from: hz, target: hz, progress: 1.0, value: hz }
+}
+
+pub struct Crush<S> {
+    source: S,
+    bits: u32,
+    downsample: u32,
+
+    counter: u32,
+    held: f32,
+}
+
+impl<S> Source for Crush<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        if self.counter == 0 {
+            self.held = self.source.sample();
+        }
+        self.counter = (self.counter + 1) % self.downsample;
+    }
+
+    fn sample(&self) -> f32 {
+        // f32 can't resolve more levels than its mantissa anyway.
+        if self.bits >= f32::MANTISSA_DIGITS {
+            return self.held;
+        }
+        // Mid-rise, so even 1 bit gives two levels rather than snapping to zero.
+        let levels = (1u32 << self.bits) as f32;
+        let level = ((self.held + 1.0) / 2.0 * levels).floor().clamp(0.0, levels - 1.0);
+        (level + 0.5) / levels * 2.0 - 1.0
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.counter = 0;
+        self.held = 0.0;
+    }
+}
+
+// Transfer functions for Shaper, all continuous, monotonic, and mapping 0 to 0.
+#[derive(Copy, Clone, Debug)]
+pub enum ShaperKind {
+    HardClip,
+    // tanh
+    SoftClip,
+    Arctan,
+    // 1.5x - 0.5x^3, which reaches exactly +-1 at +-1 with zero slope.
+    Cubic,
+}
+
+impl ShaperKind {
+    pub fn shape(self, x: f32) -> f32 {
+        match self {
+            Self::HardClip => x.clamp(-1.0, 1.0),
+            Self::SoftClip => x.tanh(),
+            Self::Arctan => x.atan() * std::f32::consts::FRAC_2_PI,
+            Self::Cubic => {
+                let x = x.clamp(-1.0, 1.0);
+                1.5 * x - 0.5 * x * x * x
+            }
+        }
+    }
+}
+
+pub struct Shaper<S> {
+    source: S,
+    amount: f32,
+    kind: ShaperKind,
+}
+
+impl<S> Source for Shaper<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        self.kind.shape(self.source.sample() * self.amount)
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ArpPattern {
+    Up,
+    Down,
+    // Bounces between the ends without repeating them.
+    UpDown,
+    Random,
+}
+
+// Steps through notes at a fixed rate on a single retriggered voice.
+pub struct Arpeggiator {
+    notes: Vec<Note>,
+    octaves: u32,
+    steps_per_sec: f32,
+    pattern: ArpPattern,
+    seed: u32,
+    rng: XorShift,
+
+    // Counted in samples so steps land exactly on sample boundaries.
+    samples: u64,
+    step: Option<u64>,
+    note: Option<Note>,
+    osc: Sine<f32>,
+    envelope: ADSR,
+}
+
+impl Arpeggiator {
+    // Repeats the notes over this many octaves going up.
+    pub fn octaves(self, octaves: u32) -> Self {
+        Self { octaves: octaves.max(1), ..self }
+    }
+
+    pub fn with_envelope(self, envelope: ADSR) -> Self {
+        Self { envelope, ..self }
+    }
+
+    pub fn seeded(self, seed: u32) -> Self {
+        Self { seed, rng: XorShift::new(seed), ..self }
+    }
+
+    pub fn current_note(&self) -> Option<Note> {
+        self.note
+    }
+
+    fn note_at(&mut self, step: u64) -> Note {
+        let len = self.notes.len() as u64 * self.octaves as u64;
+        let index = match self.pattern {
+            ArpPattern::Up => step % len,
+            ArpPattern::Down => len - 1 - step % len,
+            ArpPattern::UpDown if len > 1 => {
+                let index = step % (2 * len - 2);
+                if index < len { index } else { 2 * len - 2 - index }
+            }
+            ArpPattern::UpDown => 0,
+            ArpPattern::Random => self.rng.next_u32() as u64 % len,
+        };
+        let base = self.notes[(index % self.notes.len() as u64) as usize];
+        Note(base.0 + 12 * (index / self.notes.len() as u64) as i32)
+    }
+}
+
+impl Source for Arpeggiator {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        if !self.notes.is_empty() {
+            // The step that the start of this sample falls in.
+            let step = (self.samples as f64 * self.steps_per_sec as f64 / elapsed.rate as f64) as u64;
+            if self.step != Some(step) {
+                self.step = Some(step);
+                let note = self.note_at(step);
+                self.note = Some(note);
+                // Only the frequency changes, the phase carries on so there's no gap.
+                self.osc.hz = note.hz();
+                self.envelope.gate_on();
+            }
+        }
+        self.samples += elapsed.count;
+        self.osc.update(elapsed);
+        self.envelope.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        self.osc.sample() * self.envelope.sample()
+    }
+
+    fn reset(&mut self) {
+        self.rng = XorShift::new(self.seed);
+        self.samples = 0;
+        self.step = None;
+        self.note = None;
+        self.osc = sine(0.0);
+        self.envelope.reset();
+    }
+}
+
+pub fn arpeggiator(notes: &[Note], steps_per_sec: f32, pattern: ArpPattern) -> Arpeggiator {
+    Arpeggiator {
+        notes: notes.to_vec(),
+        octaves: 1,
+        steps_per_sec,
+        pattern,
+        seed: 1,
+        rng: XorShift::new(1),
+        samples: 0,
+        step: None,
+        note: None,
+        osc: sine(0.0),
+        envelope: adsr_gated(0.005, 0.06, 0.7, 0.14),
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum FadeLaw {
+    // Gains sum to 1, which dips in loudness mid fade for uncorrelated sources.
+    Linear,
+    // Squared gains sum to 1, so perceived loudness stays constant.
+    EqualPower,
+}
+
+pub struct Crossfade<A, B, Mix> {
+    a: A,
+    b: B,
+    mix: Mix,
+    law: FadeLaw,
+}
+
+impl<A, B, Mix> Crossfade<A, B, Mix> {
+    pub fn linear(self) -> Self {
+        Self { law: FadeLaw::Linear, ..self }
+    }
+}
+
+impl<A, B, Mix> Source for Crossfade<A, B, Mix>
+    where A: Source<Sample=f32>,
+          B: Source<Sample=f32>,
+          Mix: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.a.update(elapsed);
+        self.b.update(elapsed);
+        self.mix.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        let mix = self.mix.sample().clamp(0.0, 1.0);
+        let (a_gain, b_gain) = match self.law {
+            FadeLaw::Linear => (1.0 - mix, mix),
+            FadeLaw::EqualPower => {
+                let angle = mix * std::f32::consts::FRAC_PI_2;
+                (angle.cos(), angle.sin())
+            }
+        };
+        a_gain * self.a.sample() + b_gain * self.b.sample()
+    }
+
+    fn reset(&mut self) {
+        self.a.reset();
+        self.b.reset();
+        self.mix.reset();
+    }
+}
+
+pub struct OneShot<S> {
+    source: S,
+    duration_secs: f32,
+
+    samples: u64,
+    playing: bool,
+    finished: bool,
+}
+
+impl<S> Source for OneShot<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        let total = (self.duration_secs as f64 * elapsed.rate as f64).round() as u64;
+        self.playing = self.samples < total;
+        self.samples += elapsed.count;
+        self.finished = self.samples >= total;
+        if self.playing {
+            self.source.update(elapsed);
+        }
+    }
+
+    fn sample(&self) -> f32 {
+        if self.playing { self.source.sample() } else { 0.0 }
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        min_length(self.source.length(), Some(SampleTime::from_secs(self.duration_secs)))
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.samples = 0;
+        self.playing = false;
+        self.finished = false;
+    }
+
+    fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+// Circular buffer of past samples, for effects reading a variable distance back in time.
+struct DelayLine {
+    buffer: Vec<f32>,
+    // Where the next write goes, which is also the oldest sample.
+    pos: usize,
+}
+
+impl DelayLine {
+    fn new(len: usize) -> Self {
+        Self { buffer: vec![0.0; len.max(1)], pos: 0 }
+    }
+
+    fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn write(&mut self, value: f32) {
+        self.buffer[self.pos] = value;
+        self.pos = (self.pos + 1) % self.buffer.len();
+    }
+
+    // The sample written `delay` writes ago, in 1..=len().
+    fn read(&self, delay: usize) -> f32 {
+        let len = self.buffer.len();
+        let delay = delay.clamp(1, len);
+        self.buffer[(self.pos + len - delay) % len]
+    }
+
+    // Linearly interpolated, so smoothly modulated delays don't step.
+    fn read_interpolated(&self, delay: f32) -> f32 {
+        let delay = delay.clamp(1.0, self.buffer.len() as f32);
+        let whole = delay as usize;
+        let current = self.read(whole);
+        let next = self.read(whole + 1);
+        current + (next - current) * delay.fract()
+    }
+}
+
+// Lowpass filtered feedback comb, the damping is what makes high frequencies die out first.
+struct Comb {
+    delay: DelayLine,
+    filtered: f32,
+}
+
+impl Comb {
+    fn process(&mut self, x: f32, feedback: f32, damp: f32) -> f32 {
+        let output = self.delay.read(self.delay.len());
+        self.filtered = output * (1.0 - damp) + self.filtered * damp;
+        self.delay.write(x + self.filtered * feedback);
+        output
+    }
+}
+
+struct Allpass {
+    delay: DelayLine,
+}
+
+impl Allpass {
+    fn process(&mut self, x: f32) -> f32 {
+        let delayed = self.delay.read(self.delay.len());
+        self.delay.write(x + delayed * 0.5);
+        delayed - x
+    }
+}
+
+pub struct Reverb<S> {
+    source: S,
+    room: f32,
+    damp: f32,
+    wet: f32,
+
+    // Buffers are sized from the rate seen in update, so they're empty until then.
+    rate: u32,
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+    output: f32,
+}
+
+impl<S> Source for Reverb<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        // Freeverb's tunings, in samples at 44.1kHz.
+        const COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+        const ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+
+        self.source.update(elapsed);
+        if self.rate != elapsed.rate {
+            self.rate = elapsed.rate;
+            let scale = |len: usize| len * elapsed.rate as usize / 44100;
+            self.combs = COMBS.iter().map(|&len| Comb { delay: DelayLine::new(scale(len)), filtered: 0.0 }).collect();
+            self.allpasses = ALLPASSES.iter().map(|&len| Allpass { delay: DelayLine::new(scale(len)) }).collect();
+        }
+
+        let dry = self.source.sample();
+        // Feedback tops out at 0.98, so the tail always decays.
+        let feedback = 0.7 + self.room.clamp(0.0, 1.0) * 0.28;
+        let damp = self.damp.clamp(0.0, 1.0) * 0.4;
+        let input = dry * 0.015;
+        let mut wet: f32 = self.combs.iter_mut().map(|comb| comb.process(input, feedback, damp)).sum();
+        for allpass in &mut self.allpasses {
+            wet = allpass.process(wet);
+        }
+        self.output = dry * (1.0 - self.wet) + wet * 3.0 * self.wet;
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        // Rebuilds the combs and allpasses on the next update.
+        self.rate = 0;
+        self.output = 0.0;
+    }
+}
+
+// Smooths the absolute level of a signal, rising with attack_secs and falling with
+// release_secs as time constants.
+struct Follower {
+    attack_secs: f32,
+    release_secs: f32,
+    envelope: f32,
+}
+
+impl Follower {
+    fn process(&mut self, x: f32, rate: u32) -> f32 {
+        let level = x.abs();
+        let secs = if level > self.envelope { self.attack_secs } else { self.release_secs };
+        let coeff = if secs > 0.0 { (-1.0 / (secs * rate as f32)).exp() } else { 0.0 };
+        self.envelope = level + coeff * (self.envelope - level);
+        self.envelope
+    }
+}
+
+fn follower(attack_secs: f32, release_secs: f32) -> Follower {
+    Follower { attack_secs, release_secs, envelope: 0.0 }
+}
+
+pub struct EnvFollower<S> {
+    source: S,
+    follower: Follower,
+}
+
+impl<S> Source for EnvFollower<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        self.follower.process(self.source.sample(), elapsed.rate);
+    }
+
+    fn sample(&self) -> f32 {
+        self.follower.envelope
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.follower.envelope = 0.0;
+    }
+}
+
+pub struct Gate<S> {
+    source: S,
+    open_threshold: f32,
+    close_threshold: f32,
+    level: Follower,
+    // Smooths the 0 or 1 target, so the gain ramps rather than steps.
+    gain: Follower,
+    open: bool,
+    output: f32,
+}
+
+impl<S> Gate<S> {
+    // Where the open gate closes, instead of half the threshold.
+    pub fn with_hysteresis(mut self, close_threshold: f32) -> Self {
+        self.close_threshold = close_threshold.min(self.open_threshold);
+        self
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+}
+
+impl<S> Source for Gate<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        let x = self.source.sample();
+        let level = self.level.process(x, elapsed.rate);
+        if self.open {
+            self.open = level >= self.close_threshold;
+        } else {
+            self.open = level >= self.open_threshold;
+        }
+        let gain = self.gain.process(if self.open { 1.0 } else { 0.0 }, elapsed.rate);
+        self.output = x * gain;
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.level.envelope = 0.0;
+        self.gain.envelope = 0.0;
+        self.open = false;
+        self.output = 0.0;
+    }
+}
+
+pub struct Compressor<S> {
+    source: S,
+    threshold: f32,
+    ratio: f32,
+    follower: Follower,
+    output: f32,
+}
+
+impl<S> Source for Compressor<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        let x = self.source.sample();
+        let envelope = self.follower.process(x, elapsed.rate);
+        // Untouched below the threshold, above it every ratio dB in becomes 1dB out.
+        let gain = if envelope > self.threshold {
+            let over_db = 20.0 * (envelope / self.threshold).log10();
+            let reduction_db = over_db * (1.0 - 1.0 / self.ratio.max(1.0));
+            10f32.powf(-reduction_db / 20.0)
+        } else {
+            1.0
+        };
+        self.output = x * gain;
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.follower.envelope = 0.0;
+        self.output = 0.0;
+    }
+}
+
+pub struct Map<S, F> {
+    source: S,
+    f: F,
+    // f is FnMut, so it's applied in update rather than sample.
+    output: f32,
+}
+
+impl<S, F> Source for Map<S, F>
+    where S: Source<Sample=f32>,
+          F: FnMut(f32) -> f32,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        self.output = (self.f)(self.source.sample());
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.output = 0.0;
+    }
+}
+
+pub struct Widen<S> {
+    source: S,
+    width: f32,
+}
+
+impl<S> Source for Widen<S>
+    where S: Source<Sample=[f32; 2]>,
+{
+    type Sample = [f32; 2];
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+    }
+
+    fn sample(&self) -> [f32; 2] {
+        let [left, right] = self.source.sample();
+        let mid = (left + right) / 2.0;
+        let side = (left - right) / 2.0 * self.width;
+        [mid + side, mid - side]
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+    }
+}
+
+// In place iterative radix-2 FFT, len must be a power of two. Inverse leaves the result
+// unscaled, divide by len to get the original back.
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let len = re.len();
+    assert!(len.is_power_of_two() && im.len() == len);
+    let bits = len.trailing_zeros();
+    for index in 0..len {
+        let reversed = index.reverse_bits() >> (usize::BITS - bits);
+        if index < reversed {
+            re.swap(index, reversed);
+            im.swap(index, reversed);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut size = 2;
+    while size <= len {
+        let angle = sign * std::f32::consts::TAU / size as f32;
+        for start in (0..len).step_by(size) {
+            for k in 0..size / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let even = start + k;
+                let odd = even + size / 2;
+                let odd_re = re[odd] * cos - im[odd] * sin;
+                let odd_im = re[odd] * sin + im[odd] * cos;
+                re[odd] = re[even] - odd_re;
+                im[odd] = im[even] - odd_im;
+                re[even] += odd_re;
+                im[even] += odd_im;
+            }
+        }
+        size *= 2;
+    }
+}
+
+// Transparent tap keeping recent samples for inspecting a source's frequency content. Nothing
+// is allocated or computed while rendering, only when reading the magnitudes.
+pub struct Spectrum<S> {
+    source: S,
+    history: DelayLine,
+    rate: u32,
+}
+
+impl<S> Spectrum<S> {
+    // Hann windowed magnitudes of the most recent samples, for bins 0 up to Nyquist.
+    pub fn magnitudes(&self) -> Vec<f32> {
+        let len = self.history.len();
+        let mut re: Vec<f32> = (0..len)
+            .map(|index| {
+                let window = 0.5 - 0.5 * (std::f32::consts::TAU * index as f32 / len as f32).cos();
+                self.history.read(len - index) * window
+            })
+            .collect();
+        let mut im = vec![0.0; len];
+        fft(&mut re, &mut im, false);
+        (0..len / 2).map(|bin| re[bin].hypot(im[bin]) * 2.0 / len as f32).collect()
+    }
+
+    pub fn bin_hz(&self, bin: usize) -> f32 {
+        bin as f32 * self.rate as f32 / self.history.len() as f32
+    }
+}
+
+impl<S> Source for Spectrum<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        self.rate = elapsed.rate;
+        self.history.write(self.source.sample());
+    }
+
+    fn sample(&self) -> f32 {
+        self.source.sample()
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.history = DelayLine::new(self.history.len());
+        self.rate = 0;
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PatchOscillator {
+    Sine,
+    Square,
+    Saw,
+    Triangle,
+    Noise,
+}
+
+impl PatchOscillator {
+    pub const ALL: [Self; 5] = [Self::Sine, Self::Square, Self::Saw, Self::Triangle, Self::Noise];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sine => "sine",
+            Self::Square => "square",
+            Self::Saw => "saw",
+            Self::Triangle => "triangle",
+            Self::Noise => "noise",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PatchEffect {
+    Gain(f32),
+    LowPass(f32),
+    HighPass(f32),
+    Echo { delay_secs: f32, feedback: f32 },
+    Reverb { room: f32, damp: f32, wet: f32 },
+}
+
+impl PatchEffect {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gain(_) => "gain",
+            Self::LowPass(_) => "low_pass",
+            Self::HighPass(_) => "high_pass",
+            Self::Echo { .. } => "echo",
+            Self::Reverb { .. } => "reverb",
+        }
+    }
+
+    pub fn params(&self) -> Vec<f32> {
+        match *self {
+            Self::Gain(gain) => vec![gain],
+            Self::LowPass(cutoff) | Self::HighPass(cutoff) => vec![cutoff],
+            Self::Echo { delay_secs, feedback } => vec![delay_secs, feedback],
+            Self::Reverb { room, damp, wet } => vec![room, damp, wet],
+        }
+    }
+
+    pub fn from_params(name: &str, params: &[f32]) -> Option<Self> {
+        Some(match (name, params) {
+            ("gain", &[gain]) => Self::Gain(gain),
+            ("low_pass", &[cutoff]) => Self::LowPass(cutoff),
+            ("high_pass", &[cutoff]) => Self::HighPass(cutoff),
+            ("echo", &[delay_secs, feedback]) => Self::Echo { delay_secs, feedback },
+            ("reverb", &[room, damp, wet]) => Self::Reverb { room, damp, wet },
+            _ => return None,
+        })
+    }
+}
+
+// A sound described as data, so presets can be loaded from text instead of being compiled in.
+// An enveloped oscillator, then each effect in order. Presets use a custom text format, see
+// the FromStr impl below, rather than serde.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Patch {
+    pub oscillator: PatchOscillator,
+    pub hz: f32,
+    pub attack_secs: f32,
+    pub decay_secs: f32,
+    pub sustain_level: f32,
+    pub release_secs: f32,
+    pub duration_secs: f32,
+    pub effects: Vec<PatchEffect>,
+}
+
+impl Default for Patch {
+    fn default() -> Self {
+        Self {
+            oscillator: PatchOscillator::Sine,
+            hz: 440.0,
+            attack_secs: 0.125,
+            // The old rate of 15 per second, over the 0.4 from full level down to sustain.
+            decay_secs: 0.4 / 15.0,
+            sustain_level: 0.6,
+            release_secs: 0.6,
+            duration_secs: 1.0,
+            effects: Vec::new(),
+        }
+    }
+}
+
+impl Patch {
+    pub fn build(&self) -> Box<dyn Source<Sample=f32> + Send> {
+        let oscillator: Box<dyn Source<Sample=f32> + Send> = match self.oscillator {
+            PatchOscillator::Sine => Box::new(sine(self.hz)),
+            PatchOscillator::Square => Box::new(square(self.hz)),
+            PatchOscillator::Saw => Box::new(saw(self.hz)),
+            PatchOscillator::Triangle => Box::new(triangle(self.hz)),
+            PatchOscillator::Noise => Box::new(noise()),
+        };
+        let envelope = adsr(
+            0.0..self.duration_secs,
+            self.attack_secs,
+            self.decay_secs,
+            self.sustain_level,
+            self.release_secs,
+        );
+        let mut source: Box<dyn Source<Sample=f32> + Send> = Box::new(oscillator.gain(envelope));
+        for effect in &self.effects {
+            source = match *effect {
+                PatchEffect::Gain(gain) => Box::new(source.gain(gain)),
+                PatchEffect::LowPass(cutoff) => Box::new(source.low_pass(cutoff)),
+                PatchEffect::HighPass(cutoff) => Box::new(source.high_pass(cutoff, std::f32::consts::FRAC_1_SQRT_2)),
+                PatchEffect::Echo { delay_secs, feedback } => Box::new(source.echo(delay_secs, feedback)),
+                PatchEffect::Reverb { room, damp, wet } => Box::new(source.reverb(room, damp, wet)),
+            };
+        }
+        source
+    }
+}
+
+#[derive(Debug)]
+pub enum ParsePatchError {
+    // Line numbers are 1 based.
+    MissingEquals(usize),
+    UnknownField(usize, String),
+    InvalidValue(usize, String),
+}
+
+impl std::fmt::Display for ParsePatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingEquals(line) => write!(f, "line {line}: expected `field = value`"),
+            Self::UnknownField(line, field) => write!(f, "line {line}: unknown field {field:?}"),
+            Self::InvalidValue(line, value) => write!(f, "line {line}: invalid value {value:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ParsePatchError {}
+
+// The preset format: one `field = value` per line, with `#` comments. Omitted fields keep
+// their defaults, unknown fields are an error, and each `effect = name params...` line
+// appends to the chain, e.g.:
+//
+//     oscillator = saw
+//     hz = 220
+//     effect = low_pass 800
+//     effect = echo 0.3 0.5
+impl std::str::FromStr for Patch {
+    type Err = ParsePatchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut patch = Patch::default();
+        for (index, line) in s.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (field, value) = line.split_once('=').ok_or(ParsePatchError::MissingEquals(line_number))?;
+            let (field, value) = (field.trim(), value.trim());
+            let invalid = || ParsePatchError::InvalidValue(line_number, value.to_string());
+            let number = || value.parse::<f32>().map_err(|_| invalid());
+            match field {
+                "oscillator" => {
+                    patch.oscillator = PatchOscillator::ALL.into_iter()
+                        .find(|oscillator| oscillator.name() == value)
+                        .ok_or_else(invalid)?;
+                }
+                "hz" => patch.hz = number()?,
+                "attack" => patch.attack_secs = number()?,
+                "decay" => patch.decay_secs = number()?,
+                "sustain" => patch.sustain_level = number()?,
+                "release" => patch.release_secs = number()?,
+                "duration" => patch.duration_secs = number()?,
+                "effect" => {
+                    let mut words = value.split_whitespace();
+                    let name = words.next().unwrap_or_default();
+                    let params = words.map(str::parse).collect::<Result<Vec<f32>, _>>().map_err(|_| invalid())?;
+                    patch.effects.push(PatchEffect::from_params(name, &params).ok_or_else(invalid)?);
+                }
+                field => return Err(ParsePatchError::UnknownField(line_number, field.to_string())),
+            }
+        }
+        Ok(patch)
+    }
+}
+
+// Writes every field, in the format FromStr reads back.
+impl std::fmt::Display for Patch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "oscillator = {}", self.oscillator.name())?;
+        writeln!(f, "hz = {}", self.hz)?;
+        writeln!(f, "attack = {}", self.attack_secs)?;
+        writeln!(f, "decay = {}", self.decay_secs)?;
+        writeln!(f, "sustain = {}", self.sustain_level)?;
+        writeln!(f, "release = {}", self.release_secs)?;
+        writeln!(f, "duration = {}", self.duration_secs)?;
+        for effect in &self.effects {
+            write!(f, "effect = {}", effect.name())?;
+            for param in effect.params() {
+                write!(f, " {param}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum MidiFileError {
+    Io(std::io::Error),
+    NotMidi,
+    Truncated,
+    // A data byte with no running status to apply it to.
+    MissingStatus,
+}
+
+impl std::fmt::Display for MidiFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "couldn't read MIDI file: {error}"),
+            Self::NotMidi => write!(f, "not a standard MIDI file"),
+            Self::Truncated => write!(f, "MIDI file is truncated"),
+            Self::MissingStatus => write!(f, "MIDI event without a status byte"),
+        }
+    }
+}
+
+impl std::error::Error for MidiFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// Big endian cursor over a MIDI file's bytes.
+struct MidiReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> MidiReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MidiFileError> {
+        if self.bytes.len() < len {
+            return Err(MidiFileError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, MidiFileError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MidiFileError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, MidiFileError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // Variable length quantity: 7 bits per byte, high bit set on all but the last.
+    fn vlq(&mut self) -> Result<u32, MidiFileError> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = value << 7 | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(value)
+    }
+
+    fn u32_prefixed(&mut self) -> Result<&'a [u8], MidiFileError> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+
+    fn vlq_prefixed(&mut self) -> Result<&'a [u8], MidiFileError> {
+        let len = self.vlq()?;
+        self.take(len as usize)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MidiEvent {
+    NoteOn { note: Note, velocity: u8 },
+    NoteOff(Note),
+    // True while the pedal is down.
+    Sustain(bool),
+}
+
+impl MidiEvent {
+    // None for anything but note on and off and the sustain pedal, on any channel.
+    pub fn from_message(status: u8, first: u8, second: u8) -> Option<Self> {
+        match (status & 0xf0, second) {
+            (0x90, velocity) if velocity > 0 => Some(Self::NoteOn { note: Note::from_midi(first), velocity }),
+            // Note on with zero velocity is the usual way to send note off.
+            (0x80 | 0x90, _) => Some(Self::NoteOff(Note::from_midi(first))),
+            // Control change 64, where 64 and up is down.
+            (0xb0, value) if first == 64 => Some(Self::Sustain(value >= 64)),
+            _ => None,
+        }
+    }
+
+    // A complete message as delivered by a MIDI input port, without running status.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [status, first, second] => Self::from_message(status, first, second),
+            _ => None,
+        }
+    }
+}
+
+// What a track chunk contains, before ticks are converted to time.
+enum TrackEvent {
+    Midi(MidiEvent),
+    // Microseconds per quarter note.
+    Tempo(u32),
+}
+
+// Note and sustain pedal events from every track of a standard MIDI file, merged and timed in
+// seconds. Other messages, such as program and other control changes, are skipped.
+pub struct MidiFile {
+    // Sorted by time.
+    events: Vec<(f64, MidiEvent)>,
+}
+
+impl MidiFile {
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, MidiFileError> {
+        Self::parse(&std::fs::read(path).map_err(MidiFileError::Io)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, MidiFileError> {
+        let mut reader = MidiReader { bytes };
+        if reader.take(4).map_err(|_| MidiFileError::NotMidi)? != b"MThd" {
+            return Err(MidiFileError::NotMidi);
+        }
+        let mut header = MidiReader { bytes: reader.u32_prefixed()? };
+        let _format = header.u16()?;
+        let _tracks = header.u16()?;
+        let division = header.u16()?;
+
+        // (tick, track, event), so sorting keeps each track's own order for equal ticks.
+        let mut events = Vec::new();
+        let mut track = 0;
+        while !reader.bytes.is_empty() {
+            let id = reader.take(4)?;
+            let chunk = reader.u32_prefixed()?;
+            // Unknown chunks are allowed by the spec, and should be skipped.
+            if id == b"MTrk" {
+                parse_track(chunk, track, &mut events)?;
+                track += 1;
+            }
+        }
+        events.sort_by_key(|&(tick, track, _)| (tick, track));
+
+        // Tempo changes apply to every track, so ticks become seconds only after merging.
+        let secs_per_tick = |tempo: u32| -> f64 {
+            if division & 0x8000 == 0 {
+                tempo as f64 / 1_000_000.0 / division.max(1) as f64
+            } else {
+                // SMPTE: negative frames per second in the high byte, ticks per frame in the low.
+                let fps = -((division >> 8) as u8 as i8) as f64;
+                1.0 / (fps * (division & 0xff).max(1) as f64)
+            }
+        };
+        // 120bpm until the first tempo event.
+        let mut tempo = 500_000;
+        let (mut last_tick, mut secs) = (0u64, 0f64);
+        let mut timed = Vec::new();
+        for (tick, _, event) in events {
+            secs += (tick - last_tick) as f64 * secs_per_tick(tempo);
+            last_tick = tick;
+            match event {
+                TrackEvent::Midi(event) => timed.push((secs, event)),
+                TrackEvent::Tempo(microseconds) => tempo = microseconds,
+            }
+        }
+        return Ok(Self { events: timed });
+
+        fn parse_track(bytes: &[u8], track: usize, events: &mut Vec<(u64, usize, TrackEvent)>) -> Result<(), MidiFileError> {
+            let mut reader = MidiReader { bytes };
+            let mut tick = 0u64;
+            let mut running_status = None;
+            while !reader.bytes.is_empty() {
+                tick += reader.vlq()? as u64;
+                let mut status = reader.u8()?;
+                // Meta and sysex events cancel running status, so data bytes straight after
+                // them have nothing to apply to.
+                match status {
+                    0xff => {
+                        running_status = None;
+                        let kind = reader.u8()?;
+                        let data = reader.vlq_prefixed()?;
+                        match (kind, data) {
+                            (0x51, &[a, b, c]) => events.push((tick, track, TrackEvent::Tempo(u32::from_be_bytes([0, a, b, c])))),
+                            (0x2f, _) => break,
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    0xf0 | 0xf7 => {
+                        running_status = None;
+                        reader.vlq_prefixed()?;
+                        continue;
+                    }
+                    _ => {}
+                }
+                let first = if status & 0x80 == 0 {
+                    let data = status;
+                    status = running_status.ok_or(MidiFileError::MissingStatus)?;
+                    data
+                } else {
+                    running_status = Some(status);
+                    reader.u8()?
+                };
+                // Program change and channel pressure have one data byte, the rest two.
+                let second = if let 0xc0 | 0xd0 = status & 0xf0 { 0 } else { reader.u8()? };
+                let Some(event) = MidiEvent::from_message(status, first, second) else {
+                    continue;
+                };
+                events.push((tick, track, TrackEvent::Midi(event)));
+            }
+            Ok(())
+        }
+    }
+
+    // The first sample at or after the event, matching when MidiPlayer triggers it.
+    pub fn sample_offsets(&self, rate: u32) -> impl Iterator<Item=(u64, MidiEvent)> + '_ {
+        self.events.iter().map(move |&(secs, event)| ((secs * rate as f64).ceil() as u64, event))
+    }
+}
+
+// The sending half of a MidiInput, for whatever thread receives MIDI, such as a port
+// library's callback. Sending never blocks, and events wait in the channel until the audio
+// thread's next update. There's no port backend here, so tests and callers can drive a Synth
+// with the same messages a port would deliver.
+#[derive(Clone)]
+pub struct MidiSender(std::sync::mpsc::Sender<MidiEvent>);
+
+impl MidiSender {
+    pub fn send(&self, event: MidiEvent) {
+        // The input has been dropped, so there's nothing left to play the event.
+        let _ = self.0.send(event);
+    }
+
+    // Raw message bytes, as a port library such as midir hands them over. Anything that isn't
+    // note on or off or the sustain pedal is ignored.
+    pub fn send_bytes(&self, bytes: &[u8]) {
+        if let Some(event) = MidiEvent::from_bytes(bytes) {
+            self.send(event);
+        }
+    }
+}
+
+// Plays live MIDI events through a Synth. Events take effect at the start of the next
+// update, so timing is quantized to the audio callback's sample.
+pub struct MidiInput {
+    receiver: std::sync::mpsc::Receiver<MidiEvent>,
+    synth: Synth,
+}
+
+impl Source for MidiInput {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        while let Ok(event) = self.receiver.try_recv() {
+            self.synth.play_event(event);
+        }
+        self.synth.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        self.synth.sample()
+    }
+
+    fn reset(&mut self) {
+        while self.receiver.try_recv().is_ok() {}
+        self.synth.reset();
+    }
+}
+
+pub fn midi_input(synth: Synth) -> (MidiSender, MidiInput) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    (MidiSender(sender), MidiInput { receiver, synth })
+}
+
+// Plays a MidiFile through a Synth, like Sequence but timed in seconds.
+pub struct MidiPlayer {
+    file: MidiFile,
+    next_event: usize,
+    synth: Synth,
+    samples: u64,
+}
+
+impl Source for MidiPlayer {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        let secs = self.samples as f64 / elapsed.rate as f64;
+        while let Some(&(start, event)) = self.file.events.get(self.next_event) {
+            if start > secs {
+                break;
+            }
+            self.synth.play_event(event);
+            self.next_event += 1;
+        }
+        self.synth.update(elapsed);
+        self.samples += elapsed.count;
+    }
+
+    fn sample(&self) -> f32 {
+        self.synth.sample()
+    }
+
+    fn reset(&mut self) {
+        self.next_event = 0;
+        self.synth.reset();
+        self.samples = 0;
+    }
+}
+
+pub fn midi_player(file: MidiFile, synth: Synth) -> MidiPlayer {
+    MidiPlayer { file, next_event: 0, synth, samples: 0 }
+}
+
+pub struct Chorus<S> {
+    source: S,
+    rate: f32,
+    depth: f32,
+    mix: f32,
+    // One LFO per voice, each a little faster than the last so they don't move together.
+    phases: Vec<f32>,
+
+    // The delay is sized from the rate seen in update, so it's a placeholder until then.
+    sample_rate: u32,
+    delay: DelayLine,
+    output: [f32; 2],
+}
+
+impl<S> Source for Chorus<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = [f32; 2];
+
+    fn update(&mut self, elapsed: SampleTime) {
+        const CENTER_SECS: f32 = 0.02;
+        const SWEEP_SECS: f32 = 0.01;
+
+        self.source.update(elapsed);
+        let rate = elapsed.rate as f32;
+        if self.sample_rate != elapsed.rate {
+            self.sample_rate = elapsed.rate;
+            self.delay = DelayLine::new(((CENTER_SECS + SWEEP_SECS) * rate) as usize + 2);
+        }
+        let dry = self.source.sample();
+        self.delay.write(dry);
+
+        let mut wet = [0.0; 2];
+        for (voice, phase) in self.phases.iter_mut().enumerate() {
+            *phase = advance_phase(*phase, self.rate * (1.0 + 0.13 * voice as f32), elapsed);
+            for (channel, offset) in [0.0, 0.25].into_iter().enumerate() {
+                let sweep = Waveform::Sine.at((*phase + offset).fract()) * self.depth * SWEEP_SECS;
+                wet[channel] += self.delay.read_interpolated((CENTER_SECS + sweep) * rate);
+            }
+        }
+        let voices = self.phases.len() as f32;
+        self.output = wet.map(|wet| dry * (1.0 - self.mix) + wet / voices * self.mix);
+    }
+
+    fn sample(&self) -> [f32; 2] {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        let voices = self.phases.len();
+        for (voice, phase) in self.phases.iter_mut().enumerate() {
+            *phase = voice as f32 / voices as f32;
+        }
+        // Reallocates the delay on the next update.
+        self.sample_rate = 0;
+        self.output = [0.0; 2];
+    }
+}
+
+pub struct Flanger<S> {
+    source: S,
+    rate: f32,
+    depth: f32,
+    feedback: f32,
+    mix: f32,
+    phase: f32,
+
+    // The delay is sized from the rate seen in update, so it's a placeholder until then.
+    sample_rate: u32,
+    delay: DelayLine,
+    output: f32,
+}
+
+impl<S> Source for Flanger<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        const MIN_SECS: f32 = 0.001;
+        const MAX_SECS: f32 = 0.01;
+
+        self.source.update(elapsed);
+        let rate = elapsed.rate as f32;
+        if self.sample_rate != elapsed.rate {
+            self.sample_rate = elapsed.rate;
+            self.delay = DelayLine::new((MAX_SECS * rate) as usize + 2);
+        }
+        self.phase = advance_phase(self.phase, self.rate, elapsed);
+        let sweep = (Waveform::Sine.at(self.phase) + 1.0) / 2.0 * self.depth;
+        let delayed = self.delay.read_interpolated((MIN_SECS + sweep * (MAX_SECS - MIN_SECS)) * rate);
+        let dry = self.source.sample();
+        self.delay.write(dry + self.feedback * delayed);
+        self.output = dry * (1.0 - self.mix) + delayed * self.mix;
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.phase = 0.0;
+        // Reallocates the delay on the next update.
+        self.sample_rate = 0;
+        self.output = 0.0;
+    }
+}
+
+pub struct PingPong<S> {
+    source: S,
+    delay_secs: f32,
+    feedback: f32,
+    mix: f32,
+
+    // The delays are sized from the rate seen in update, so they're placeholders until then.
+    rate: u32,
+    left: DelayLine,
+    right: DelayLine,
+    output: [f32; 2],
+}
+
+impl<S> Source for PingPong<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = [f32; 2];
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        if self.rate != elapsed.rate {
+            self.rate = elapsed.rate;
+            let len = (self.delay_secs * elapsed.rate as f32).round().max(1.0) as usize;
+            self.left = DelayLine::new(len);
+            self.right = DelayLine::new(len);
+        }
+        let dry = self.source.sample();
+        let left = self.left.read(self.left.len());
+        let right = self.right.read(self.right.len());
+        // Only the left delay is fed, each side then feeds the other.
+        self.left.write(dry + self.feedback * right);
+        self.right.write(self.feedback * left);
+        self.output = [left, right].map(|wet| dry * (1.0 - self.mix) + wet * self.mix);
+    }
+
+    fn sample(&self) -> [f32; 2] {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        // Reallocates the delays on the next update.
+        self.rate = 0;
+        self.output = [0.0; 2];
+    }
+}
+
+// Plays sources from `make` back to back, starting the next as soon as the current one
+// reports finished, so there's no gap between them.
+pub struct Repeat<S, F> {
+    make: F,
+    source: S,
+    // None for forever.
+    restarts: Option<usize>,
+    total_restarts: Option<usize>,
+}
+
+impl<S, F> Source for Repeat<S, F>
+    where S: Source<Sample=f32>,
+          F: FnMut() -> S,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        if self.source.finished() && self.restarts != Some(0) {
+            self.source = (self.make)();
+            if let Some(restarts) = &mut self.restarts {
+                *restarts -= 1;
+            }
+        }
+        self.source.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        self.source.sample()
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        let length = self.source.length()?;
+        let times = self.total_restarts? as u64 + 1;
+        Some(SampleTime { count: length.count.saturating_mul(times), rate: length.rate })
+    }
+
+    fn reset(&mut self) {
+        self.source = (self.make)();
+        self.restarts = self.total_restarts;
+    }
+
+    fn finished(&self) -> bool {
+        self.restarts == Some(0) && self.source.finished()
+    }
+}
+
+pub fn repeat<S, F>(mut make: F) -> Repeat<S, F>
+    where F: FnMut() -> S,
+{
+    Repeat { source: make(), make, restarts: None, total_restarts: None }
+}
+
+// Plays `times` sources in total, but always at least one.
+pub fn repeat_n<S, F>(times: usize, mut make: F) -> Repeat<S, F>
+    where F: FnMut() -> S,
+{
+    let restarts = Some(times.saturating_sub(1));
+    Repeat { source: make(), make, restarts, total_restarts: restarts }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+pub struct Haas<S> {
+    source: S,
+    delay_secs: f32,
+    lead: Side,
+
+    // The delay is sized from the rate seen in update, so it's a placeholder until then.
+    rate: u32,
+    delay: DelayLine,
+    output: [f32; 2],
+}
+
+impl<S> Source for Haas<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = [f32; 2];
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        if self.rate != elapsed.rate {
+            self.rate = elapsed.rate;
+            self.delay = DelayLine::new((self.delay_secs * elapsed.rate as f32).round() as usize);
+        }
+        let dry = self.source.sample();
+        let delayed = self.delay.read(self.delay.len());
+        self.delay.write(dry);
+        self.output = match self.lead {
+            Side::Left => [dry, delayed],
+            Side::Right => [delayed, dry],
+        };
+    }
+
+    fn sample(&self) -> [f32; 2] {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        // Reallocates the delay on the next update.
+        self.rate = 0;
+        self.output = [0.0; 2];
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        let length = self.source.length()?;
+        Some(SampleTime::from_secs(length.as_secs() + self.delay_secs))
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum EqBandKind {
+    Peak,
+    LowShelf,
+    HighShelf,
+}
+
+pub struct EqBand {
+    kind: EqBandKind,
+    hz: f32,
+    gain_db: f32,
+    q: f32,
+    filter: Biquad,
+}
+
+// Biquad bands in series. The poles don't depend on gain, so any gain is stable, but it's
+// clamped to +-48dB as past that the output is useless anyway.
+pub struct Equalizer<S> {
+    source: S,
+    bands: Vec<EqBand>,
+    // The coefficients only change with the rate, so they're set when it does.
+    rate: u32,
+    output: f32,
+}
+
+impl<S> Equalizer<S> {
+    pub fn band(mut self, kind: EqBandKind, hz: f32, gain_db: f32, q: f32) -> Self {
+        self.bands.push(EqBand { kind, hz, gain_db: gain_db.clamp(-48.0, 48.0), q: q.max(MIN_Q), filter: Biquad::new() });
+        self.rate = 0;
+        self
+    }
+
+    pub fn peak(self, hz: f32, gain_db: f32, q: f32) -> Self {
+        self.band(EqBandKind::Peak, hz, gain_db, q)
+    }
+
+    pub fn low_shelf(self, hz: f32, gain_db: f32, q: f32) -> Self {
+        self.band(EqBandKind::LowShelf, hz, gain_db, q)
+    }
+
+    pub fn high_shelf(self, hz: f32, gain_db: f32, q: f32) -> Self {
+        self.band(EqBandKind::HighShelf, hz, gain_db, q)
+    }
+}
+
+impl<S> Source for Equalizer<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        if self.rate != elapsed.rate {
+            self.rate = elapsed.rate;
+            for band in &mut self.bands {
+                match band.kind {
+                    EqBandKind::Peak => band.filter.set_peak(band.hz, band.gain_db, band.q, elapsed.rate),
+                    EqBandKind::LowShelf => band.filter.set_low_shelf(band.hz, band.gain_db, band.q, elapsed.rate),
+                    EqBandKind::HighShelf => band.filter.set_high_shelf(band.hz, band.gain_db, band.q, elapsed.rate),
+                }
+            }
+        }
+        self.output = self.bands.iter_mut().fold(self.source.sample(), |x, band| band.filter.process(x));
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        for band in &mut self.bands {
+            band.filter = Biquad::new();
+        }
+        self.rate = 0;
+        self.output = 0.0;
+    }
+}
+
+pub struct Oversample<S, F> {
+    source: S,
+    factor: u32,
+    shape: F,
+
+    rate: u32,
+    // 8th order Butterworth anti-aliasing lowpass, as four biquads.
+    filters: [Biquad; 4],
+    output: f32,
+}
+
+impl<S, F> Source for Oversample<S, F>
+    where S: Source<Sample=f32>,
+          F: FnMut(f32) -> f32,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        // Q of each stage of an 8th order Butterworth.
+        const QS: [f32; 4] = [0.5098, 0.6013, 0.9000, 2.5629];
+
+        let rate = elapsed.rate * self.factor;
+        if self.rate != elapsed.rate {
+            self.rate = elapsed.rate;
+            for (filter, q) in self.filters.iter_mut().zip(QS) {
+                // Under the final Nyquist, so harmonics that would fold back just below it
+                // have rolled off too.
+                filter.set_low_pass(elapsed.rate as f32 * 0.4, q, rate);
+            }
+        }
+        for _ in 0..self.factor {
+            self.source.update(SampleTime { count: elapsed.count, rate });
+            let shaped = (self.shape)(self.source.sample());
+            self.output = self.filters.iter_mut().fold(shaped, |x, filter| filter.process(x));
+        }
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.filters = std::array::from_fn(|_| Biquad::new());
+        self.rate = 0;
+        self.output = 0.0;
+    }
+}
+
+// Automation through (secs, value) breakpoints, linear between them and holding the first and
+// last values outside them, e.g. for sweeping a filter cutoff.
+pub struct Envelope {
+    // Sorted by time.
+    points: Vec<(f32, f32)>,
+    samples: u64,
+    value: f32,
+}
+
+impl Envelope {
+    pub fn value_at(&self, secs: f32) -> f32 {
+        let next = self.points.partition_point(|&(time, _)| time <= secs);
+        match (next.checked_sub(1).map(|index| self.points[index]), self.points.get(next)) {
+            (Some((start, from)), Some(&(end, to))) => from + (to - from) * (secs - start) / (end - start),
+            (Some((_, value)), None) | (None, Some(&(_, value))) => value,
+            (None, None) => 0.0,
+        }
+    }
+}
+
+impl Source for Envelope {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        // Time at the start of this sample, so the first sample is exactly the first value.
+        let secs = (self.samples as f64 / elapsed.rate as f64) as f32;
+        self.samples += elapsed.count;
+        self.value = self.value_at(secs);
+    }
+
+    fn sample(&self) -> f32 {
+        self.value
+    }
+
+    fn reset(&mut self) {
+        self.samples = 0;
+        self.value = 0.0;
+    }
+}
+
+pub fn envelope(mut points: Vec<(f32, f32)>) -> Envelope {
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Envelope { points, samples: 0, value: 0.0 }
+}
+
+// From one value to another over secs, then holding it.
+pub fn ramp(from: f32, to: f32, secs: f32) -> Envelope {
+    envelope(vec![(0.0, from), (secs, to)])
+}
+
+// Stepped random modulation: a new value every interval_secs, held until the next, in
+// min..max. The same seed always gives the same sequence.
+pub struct SampleHold {
+    interval_secs: f32,
+    min: f32,
+    max: f32,
+    seed: u32,
+    rng: XorShift,
+
+    // Counted in samples so steps land exactly on sample boundaries. The interval is
+    // rounded to samples at the rate seen in update.
+    rate: u32,
+    interval_samples: u64,
+    samples: u64,
+    step: Option<u64>,
+    value: f32,
+}
+
+impl SampleHold {
+    pub fn with_range(self, min: f32, max: f32) -> Self {
+        Self { min, max, ..self }
+    }
+
+    pub fn seeded(self, seed: u32) -> Self {
+        Self { seed, rng: XorShift::new(seed), ..self }
+    }
+}
+
+impl Source for SampleHold {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        if self.rate != elapsed.rate {
+            self.rate = elapsed.rate;
+            // At least a sample, so zero, negative and NaN intervals pick a new value every
+            // sample instead of dividing by zero.
+            self.interval_samples = ((self.interval_secs as f64 * elapsed.rate as f64).round() as u64).max(1);
+        }
+        // The interval that the start of this sample falls in.
+        let step = self.samples / self.interval_samples;
+        if self.step != Some(step) {
+            self.step = Some(step);
+            self.value = self.min + self.rng.next_unit() * (self.max - self.min);
+        }
+        self.samples += elapsed.count;
+    }
+
+    fn sample(&self) -> f32 {
+        self.value
+    }
+
+    fn reset(&mut self) {
+        self.rng = XorShift::new(self.seed);
+        self.rate = 0;
+        self.samples = 0;
+        self.step = None;
+        self.value = 0.0;
+    }
+}
+
+pub fn sample_hold(interval_secs: f32) -> SampleHold {
+    SampleHold {
+        interval_secs,
+        min: -1.0,
+        max: 1.0,
+        seed: 1,
+        rng: XorShift::new(1),
+        rate: 0,
+        interval_samples: 1,
+        samples: 0,
+        step: None,
+        value: 0.0,
+    }
+}
+
+pub struct Quantize<S> {
+    source: S,
+    scale: Scale,
+    hz: f32,
+}
+
+impl<S: Source<Sample=f32>> Source for Quantize<S> {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        let hz = self.source.sample();
+        // Zero and negative frequencies have no note, so they pass through.
+        self.hz = if hz > 0.0 { self.scale.nearest(hz).hz() } else { hz };
+    }
+
+    fn sample(&self) -> f32 {
+        self.hz
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.hz = 0.0;
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+}
+
+// The latest value of each MIDI continuous controller, shared between the MIDI thread, which
+// writes them, and CcSources in the audio graph, which read them. Atomics, so neither side
+// ever waits on the other.
+#[derive(Clone)]
+pub struct Controllers(std::sync::Arc<[std::sync::atomic::AtomicU8; 128]>);
+
+impl Default for Controllers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Controllers {
+    pub const MOD_WHEEL: u8 = 1;
+    pub const EXPRESSION: u8 = 11;
+
+    pub fn new() -> Self {
+        Self(std::sync::Arc::new(std::array::from_fn(|_| std::sync::atomic::AtomicU8::new(0))))
+    }
+
+    // Values are clamped to the 7 bit MIDI range.
+    pub fn set(&self, controller: u8, value: u8) {
+        if let Some(slot) = self.0.get(controller as usize) {
+            slot.store(value.min(127), std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self, controller: u8) -> u8 {
+        self.0.get(controller as usize).map_or(0, |slot| slot.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    // Raw message bytes from a MIDI input port, on any channel. Returns false if they weren't a
+    // control change, so the caller can pass them on, e.g. to MidiSender::send_bytes. The
+    // sustain pedal is a control change too, so pass that on as well for Synth to see it.
+    pub fn send_bytes(&self, bytes: &[u8]) -> bool {
+        match *bytes {
+            [status, controller, value] if status & 0xf0 == 0xb0 => {
+                self.set(controller, value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Follows controller, mapped from 0..=127 to min..=max. smooth_secs spreads each step out
+    // so parameters don't zipper, at the cost of some lag.
+    pub fn source(&self, controller: u8, min: f32, max: f32, smooth_secs: f32) -> CcSource {
+        let mut source = CcSource {
+            controllers: self.clone(),
+            controller,
+            min,
+            max,
+            smoothing: follower(smooth_secs, smooth_secs),
+        };
+        source.reset();
+        source
+    }
+}
+
+pub struct CcSource {
+    controllers: Controllers,
+    controller: u8,
+    min: f32,
+    max: f32,
+    // Smooths the normalized value, which is never negative, so the follower's abs() is a no-op.
+    smoothing: Follower,
+}
+
+impl CcSource {
+    pub fn target(&self) -> f32 {
+        self.controllers.get(self.controller) as f32 / 127.0
+    }
+}
+
+impl Source for CcSource {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        let target = self.target();
+        self.smoothing.process(target, elapsed.rate);
+    }
+
+    fn sample(&self) -> f32 {
+        self.min + self.smoothing.envelope * (self.max - self.min)
+    }
+
+    // Jumps straight to the controller's current value, rather than smoothing up from 0.
+    fn reset(&mut self) {
+        self.smoothing.envelope = self.target();
+    }
+}
+
+// One FM operator, a sine at hz * ratio whose phase is pushed around by the modulator's output,
+// in radians. As a modulator, its level is the modulation index, so sidebands land at
+// carrier +- k * modulator hz with Bessel function amplitudes J_k(level).
+//
+// Operators chain into DX7 style algorithms: modulated_by stacks them in series, and Add mixes
+// them in parallel, e.g. carriers `a.wrap() + b` or modulators `a.modulated_by(b.wrap() + c)`.
+pub struct FmOperator<Hz, M> {
+    // Sampled the same way as PmSine, after advancing, so they line up sample for sample.
+    sine: Sine<Mul<Hz, f32>>,
+    level: f32,
+    // Feeds the operator's own previous output back into its phase, in radians.
+    feedback: f32,
+    modulator: M,
+    output: f32,
+}
+
+impl<Hz, M> FmOperator<Hz, M> {
+    pub fn with_level(self, level: f32) -> Self {
+        Self { level, ..self }
+    }
+
+    pub fn with_feedback(self, feedback: f32) -> Self {
+        Self { feedback, ..self }
+    }
+
+    // Replaces the modulator, so operators nest into a stack.
+    pub fn modulated_by<M2>(self, modulator: M2) -> FmOperator<Hz, M2> {
+        FmOperator {
+            sine: self.sine,
+            level: self.level,
+            feedback: self.feedback,
+            modulator,
+            output: self.output,
+        }
+    }
+}
+
+impl<Hz, M> Source for FmOperator<Hz, M>
+    where Hz: Source<Sample=f32>,
+          M: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.sine.update(elapsed);
+        self.modulator.update(elapsed);
+        let modulation = self.modulator.sample() + self.feedback * self.output;
+        self.output = self.level * self.sine.sample_shifted(modulation);
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.sine.reset();
+        self.modulator.reset();
+        self.output = 0.0;
+    }
+}
+
+// An unmodulated operator at full level, until it's given a modulator.
+pub fn fm_operator<Hz>(hz: Hz, ratio: f32) -> FmOperator<Hz, f32> {
+    FmOperator { sine: sine(Mul { left: hz, right: ratio }), level: 1.0, feedback: 0.0, modulator: 0.0, output: 0.0 }
+}
+
+// The simplest algorithm: a carrier at hz, modulated by one operator at hz * ratio.
+pub fn fm_pair(hz: f32, ratio: f32, index: f32) -> FmOperator<f32, FmOperator<f32, f32>> {
+    fm_operator(hz, 1.0).modulated_by(fm_operator(hz, ratio).with_level(index))
+}
+
+pub struct PmSine<Hz, M> {
+    sine: Sine<Hz>,
+    modulator: M,
+    depth: f32,
+}
+
+impl<Hz, M> Source for PmSine<Hz, M>
+    where Hz: Source<Sample=f32>,
+          M: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.sine.update(elapsed);
+        self.modulator.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        self.sine.sample_shifted(self.depth * self.modulator.sample())
+    }
+
+    fn reset(&mut self) {
+        self.sine.reset();
+        self.modulator.reset();
+    }
+}
+
+pub struct MultiTapDelay<S> {
+    source: S,
+    taps: Vec<(f32, f32)>,
+
+    // Tap offsets in samples and the buffer are sized from the rate seen in update.
+    rate: u32,
+    offsets: Vec<usize>,
+    delay: DelayLine,
+    output: f32,
+}
+
+impl<S> Source for MultiTapDelay<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        if self.rate != elapsed.rate {
+            self.rate = elapsed.rate;
+            self.offsets = self.taps.iter()
+                .map(|&(delay_secs, _)| (delay_secs.max(0.0) * elapsed.rate as f32).round() as usize)
+                .collect();
+            // One more than the longest tap, since the current sample is written first.
+            self.delay = DelayLine::new(self.offsets.iter().max().map_or(1, |longest| longest + 1));
+        }
+        let dry = self.source.sample();
+        self.delay.write(dry);
+        // read(1) is the sample just written, so a tap of n samples reads n + 1.
+        let wet: f32 = self.offsets.iter().zip(&self.taps)
+            .map(|(offset, &(_, gain))| gain * self.delay.read(offset + 1))
+            .sum();
+        self.output = dry + wet;
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        // Reallocates the buffer on the next update.
+        self.rate = 0;
+        self.output = 0.0;
+    }
+}
+
+// Uniformly partitioned overlap-save convolution. The first BLOCK taps are convolved directly,
+// so the output isn't delayed. The tail is split into BLOCK long partitions, each convolved
+// with the matching past block of input in the frequency domain. A block's tail output is ready
+// once the block is complete, which is exactly BLOCK samples before the tail taps apply.
+pub struct ConvolutionReverb<S> {
+    source: S,
+    head: Vec<f32>,
+    // Spectra of the tail partitions, each zero padded to 2 * BLOCK.
+    partitions: Vec<(Vec<f32>, Vec<f32>)>,
+
+    history: DelayLine,
+    // The previous and current input blocks, for overlap-save.
+    input: Vec<f32>,
+    pos: usize,
+    // Spectra of recent input blocks, newest at spectra_pos, one per partition.
+    spectra: Vec<(Vec<f32>, Vec<f32>)>,
+    spectra_pos: usize,
+    // Where the partitions' products are summed, kept so the audio thread doesn't allocate.
+    sum_re: Vec<f32>,
+    sum_im: Vec<f32>,
+    tail: Vec<f32>,
+    output: f32,
+}
+
+impl<S> ConvolutionReverb<S> {
+    pub const BLOCK: usize = 128;
+
+    pub fn new(source: S, ir: Vec<f32>) -> Self {
+        let block = Self::BLOCK;
+        let split = ir.len().min(block);
+        let partitions: Vec<_> = ir[split..].chunks(block)
+            .map(|chunk| {
+                let mut re = vec![0.0; 2 * block];
+                let mut im = vec![0.0; 2 * block];
+                re[..chunk.len()].copy_from_slice(chunk);
+                fft(&mut re, &mut im, false);
+                (re, im)
+            })
+            .collect();
+        let spectra = vec![(vec![0.0; 2 * block], vec![0.0; 2 * block]); partitions.len()];
+        Self {
+            source,
+            head: ir[..split].to_vec(),
+            partitions,
+            history: DelayLine::new(block),
+            input: vec![0.0; 2 * block],
+            pos: 0,
+            spectra,
+            spectra_pos: 0,
+            sum_re: vec![0.0; 2 * block],
+            sum_im: vec![0.0; 2 * block],
+            tail: vec![0.0; block],
+            output: 0.0,
+        }
+    }
+
+    // Runs once every BLOCK samples, once the second half of input is full.
+    fn process_block(&mut self) {
+        let block = Self::BLOCK;
+        let count = self.partitions.len();
+        self.spectra_pos = (self.spectra_pos + 1) % count;
+        let (re, im) = &mut self.spectra[self.spectra_pos];
+        re.copy_from_slice(&self.input);
+        im.fill(0.0);
+        fft(re, im, false);
+
+        self.sum_re.fill(0.0);
+        self.sum_im.fill(0.0);
+        for (index, (h_re, h_im)) in self.partitions.iter().enumerate() {
+            // Partition n pairs with the input from n blocks ago.
+            let (x_re, x_im) = &self.spectra[(self.spectra_pos + count - index) % count];
+            for bin in 0..2 * block {
+                self.sum_re[bin] += x_re[bin] * h_re[bin] - x_im[bin] * h_im[bin];
+                self.sum_im[bin] += x_re[bin] * h_im[bin] + x_im[bin] * h_re[bin];
+            }
+        }
+        fft(&mut self.sum_re, &mut self.sum_im, true);
+        // The first half wrapped around, the second half is the linear convolution.
+        let scale = 1.0 / (2 * block) as f32;
+        for (out, value) in self.tail.iter_mut().zip(&self.sum_re[block..]) {
+            *out = value * scale;
+        }
+        self.input.copy_within(block.., 0);
+    }
+}
+
+impl<S> Source for ConvolutionReverb<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        let dry = self.source.sample();
+        self.history.write(dry);
+        let head: f32 = self.head.iter().enumerate()
+            .map(|(delay, gain)| gain * self.history.read(delay + 1))
+            .sum();
+        self.output = head + self.tail[self.pos];
+
+        let block = Self::BLOCK;
+        self.input[block + self.pos] = dry;
+        self.pos += 1;
+        if self.pos == block {
+            self.pos = 0;
+            if self.partitions.is_empty() {
+                return;
+            }
+            self.process_block();
+        }
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.history = DelayLine::new(Self::BLOCK);
+        self.input.fill(0.0);
+        self.pos = 0;
+        for (re, im) in &mut self.spectra {
+            re.fill(0.0);
+            im.fill(0.0);
+        }
+        self.tail.fill(0.0);
+        self.output = 0.0;
+    }
+}
+
+// A note length relative to the beat, which is a quarter note, as used for tempo synced
+// effects.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Division {
+    beats: f32,
+}
+
+impl Division {
+    // Slower tempos, along with zero, negative and NaN ones, are taken as this, so times stay
+    // finite and positive.
+    pub const MIN_BPM: f32 = 1.0;
+
+    // 1 for a whole note, 4 for a quarter, 8 for an eighth, and so on.
+    pub fn note(denominator: u32) -> Self {
+        Self { beats: 4.0 / denominator.max(1) as f32 }
+    }
+
+    // Half as long again.
+    pub fn dotted(self) -> Self {
+        Self { beats: self.beats * 1.5 }
+    }
+
+    // Three in the time of two.
+    pub fn triplet(self) -> Self {
+        Self { beats: self.beats * 2.0 / 3.0 }
+    }
+
+    pub fn secs(self, bpm: f32) -> f32 {
+        self.beats * 60.0 / bpm.max(Self::MIN_BPM)
+    }
+
+    // Rounded to the nearest sample, the same as the delays do.
+    pub fn samples(self, bpm: f32, rate: u32) -> usize {
+        (self.secs(bpm) * rate as f32).round() as usize
+    }
+}
+
+pub struct ToMono<S> {
+    source: S,
+}
+
+impl<S, const N: usize> Source for ToMono<S>
+    where S: Source<Sample=[f32; N]>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        self.source.sample().iter().sum::<f32>() / N.max(1) as f32
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+}
+
+pub struct ToStereo<S> {
+    source: S,
+}
+
+impl<S> Source for ToStereo<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = [f32; 2];
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+    }
+
+    fn sample(&self) -> [f32; 2] {
+        [self.source.sample(); 2]
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+}
+
+// A parameter that can be set from any thread while the audio graph reads it, e.g. from a UI.
+// Steps are audible, so read it through smoothed().
+#[derive(Clone)]
+pub struct Param(std::sync::Arc<std::sync::atomic::AtomicU32>);
+
+impl Param {
+    pub fn new(value: f32) -> Self {
+        Self(std::sync::Arc::new(std::sync::atomic::AtomicU32::new(value.to_bits())))
+    }
+
+    pub fn set(&self, value: f32) {
+        self.0.store(value.to_bits(), std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(std::sync::atomic::Ordering::Relaxed))
+    }
+}
+
+impl Source for Param {
+    type Sample = f32;
+
+    fn sample(&self) -> f32 {
+        self.get()
+    }
+}
+
+pub struct Smoothed<S> {
+    target: S,
+    time_secs: f32,
+    value: Option<f32>,
+}
+
+impl Smoothed<f32> {
+    // Glides from wherever the output is now.
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+    }
+}
+
+impl<S> Source for Smoothed<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.target.update(elapsed);
+        let target = self.target.sample();
+        // The coefficient comes from the elapsed time, so the glide takes as long at any rate.
+        let coeff = if self.time_secs > 0.0 { (-elapsed.as_secs() / self.time_secs).exp() } else { 0.0 };
+        self.value = Some(match self.value {
+            Some(value) => target + coeff * (value - target),
+            None => target,
+        });
+    }
+
+    fn sample(&self) -> f32 {
+        self.value.unwrap_or_else(|| self.target.sample())
+    }
+
+    fn reset(&mut self) {
+        self.target.reset();
+        self.value = None;
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.target.length()
+    }
+}
+
+pub struct PitchShift<S> {
+    source: S,
+    ratio: f32,
+
+    // The delay is sized from the rate seen in update, so it's a placeholder until then.
+    rate: u32,
+    delay: DelayLine,
+    // Of the first head through the window, the second is half a window behind.
+    phase: f32,
+    output: f32,
+}
+
+impl<S> PitchShift<S> {
+    // Longer smears transients, shorter adds a buzz at the window rate. Partials land on
+    // multiples of that rate, |ratio - 1| / WINDOW_SECS, from their original pitch, so the shift
+    // is only exact to within that.
+    pub const WINDOW_SECS: f32 = 0.05;
+}
+
+impl<S> Source for PitchShift<S>
+    where S: Source<Sample=f32>,
+{
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        self.source.update(elapsed);
+        let dry = self.source.sample();
+        if self.ratio == 1.0 {
+            self.output = dry;
+            return;
+        }
+        let window = Self::WINDOW_SECS * elapsed.rate as f32;
+        if self.rate != elapsed.rate {
+            self.rate = elapsed.rate;
+            self.delay = DelayLine::new(window as usize + 2);
+            self.phase = 0.0;
+        }
+        self.delay.write(dry);
+        // A head's delay changing by d per sample plays back at speed 1 - d.
+        self.phase = (self.phase + (1.0 - self.ratio) / window).rem_euclid(1.0);
+        self.output = [0.0, 0.5].iter()
+            .map(|offset| {
+                let phase = (self.phase + offset).fract();
+                // Hann windows half a cycle apart sum to 1, and are silent where a head jumps.
+                let gain = (phase * std::f32::consts::PI).sin().powi(2);
+                gain * self.delay.read_interpolated(1.0 + phase * window)
+            })
+            .sum();
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        // Reallocates the delay on the next update.
+        self.rate = 0;
+        self.output = 0.0;
+    }
+
+    fn length(&self) -> Option<SampleTime> {
+        self.source.length()
+    }
+}
+
+struct Grain {
+    // In buffer samples.
+    position: f64,
+    // In output samples.
+    age: u64,
+    len: u64,
+}
+
+// Overlapping Hann windowed grains read from buffer, for textures. Grains start at evenly
+// spaced times, density per second, each grain_secs long, from around position (0..=1 through
+// the buffer), spread randomly by up to spray_secs either way.
+//
+// The output is scaled by the overlap, density * grain_secs, so level doesn't depend on it.
+// Hann windows only sum flat when the overlap is a whole multiple of 2, otherwise there's some
+// ripple at the density rate.
+pub struct Granular {
+    buffer: Vec<f32>,
+    native_rate: u32,
+    grain_secs: f32,
+    density: f32,
+    position: f32,
+    pitch: f32,
+    spray_secs: f32,
+    seed: u32,
+    rng: XorShift,
+
+    grains: Vec<Grain>,
+    // Counted in samples, so grains start on exact sample boundaries.
+    samples: u64,
+    started: u64,
+    output: f32,
+}
+
+impl Granular {
+    pub fn with_position(self, position: f32) -> Self {
+        Self { position: position.clamp(0.0, 1.0), ..self }
+    }
+
+    // Resamples each grain, without changing how long it lasts.
+    pub fn with_pitch(self, semitones: f32) -> Self {
+        Self { pitch: 2f32.powf(semitones / 12.0), ..self }
+    }
+
+    pub fn with_spray(self, spray_secs: f32) -> Self {
+        Self { spray_secs: spray_secs.max(0.0), ..self }
+    }
+
+    pub fn seeded(self, seed: u32) -> Self {
+        Self { seed, rng: XorShift::new(seed), ..self }
+    }
+
+    // How many grains have started since the last reset.
+    pub fn grains_started(&self) -> u64 {
+        self.started
+    }
+
+    fn read(&self, position: f64) -> f32 {
+        let whole = position.floor();
+        let at = |index: f64| {
+            if index < 0.0 { 0.0 } else { self.buffer.get(index as usize).copied().unwrap_or(0.0) }
+        };
+        let current = at(whole);
+        current + (at(whole + 1.0) - current) * (position - whole) as f32
+    }
+}
+
+impl Source for Granular {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        let rate = elapsed.rate as f64;
+        // Grain n starts on the first sample at or after n / density seconds.
+        while self.density > 0.0 && self.samples >= (self.started as f64 * rate / self.density as f64).ceil() as u64 {
+            let center = self.position as f64 * self.buffer.len() as f64;
+            let spray = self.rng.next_bipolar() as f64 * self.spray_secs as f64 * self.native_rate as f64;
+            let len = (self.grain_secs as f64 * rate).round().max(1.0) as u64;
+            self.grains.push(Grain { position: center + spray, age: 0, len });
+            self.started += 1;
+        }
+
+        let step = self.pitch as f64 * self.native_rate as f64 / rate * elapsed.count as f64;
+        let mut sum = 0.0;
+        for grain in &self.grains {
+            let window = (std::f32::consts::PI * grain.age as f32 / grain.len as f32).sin().powi(2);
+            sum += window * self.read(grain.position);
+        }
+        for grain in &mut self.grains {
+            grain.position += step;
+            grain.age += elapsed.count;
+        }
+        self.grains.retain(|grain| grain.age < grain.len);
+
+        let overlap = self.density * self.grain_secs;
+        self.output = sum / (overlap / 2.0).max(1.0);
+        self.samples += elapsed.count;
+    }
+
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        self.rng = XorShift::new(self.seed);
+        self.grains.clear();
+        self.samples = 0;
+        self.started = 0;
+        self.output = 0.0;
+    }
+}
+
+pub fn granular(buffer: Vec<f32>, native_rate: u32, grain_secs: f32, density: f32) -> Granular {
+    Granular {
+        buffer,
+        native_rate,
+        grain_secs,
+        density,
+        position: 0.0,
+        pitch: 1.0,
+        spray_secs: 0.0,
+        seed: 1,
+        rng: XorShift::new(1),
+        grains: Vec::new(),
+        samples: 0,
+        started: 0,
+        output: 0.0,
+    }
+}
+
+// Generative melody: random notes from a scale, each held for a duration picked from weighted
+// choices, played on a sine through an envelope. The same seed always plays the same melody.
+pub struct RandomMelody {
+    scale: Scale,
+    // Inclusive, of scale degrees, so every note is in the scale.
+    degrees: std::ops::RangeInclusive<i32>,
+    durations: Vec<(Division, f32)>,
+    bpm: f32,
+    // The fraction of each note held before it's released.
+    gate: f32,
+    seed: u32,
+    rng: XorShift,
+
+    // Counted in samples, and beats summed exactly, so note starts don't drift.
+    samples: u64,
+    beats: f64,
+    next_start: u64,
+    // None once the current note has been released.
+    release_at: Option<u64>,
+    note: Option<Note>,
+    osc: Sine<f32>,
+    envelope: ADSR,
+}
+
+impl RandomMelody {
+    pub fn with_degrees(self, degrees: std::ops::RangeInclusive<i32>) -> Self {
+        Self { degrees, ..self }
+    }
+
+    pub fn with_envelope(self, envelope: ADSR) -> Self {
+        Self { envelope, ..self }
+    }
+
+    // Holds each note for this fraction of its length, in 0..=1, before releasing it. 1 holds
+    // right up to the next note.
+    pub fn with_gate(self, gate: f32) -> Self {
+        Self { gate: gate.clamp(0.0, 1.0), ..self }
+    }
+
+    pub fn seeded(self, seed: u32) -> Self {
+        Self { seed, rng: XorShift::new(seed), ..self }
+    }
+
+    pub fn current_note(&self) -> Option<Note> {
+        self.note
+    }
+
+    fn next_note(&mut self) -> (Note, Division) {
+        let span = (self.degrees.end() - self.degrees.start() + 1).max(1) as u32;
+        let degree = self.degrees.start() + (self.rng.next_u32() % span) as i32;
+        let total: f32 = self.durations.iter().map(|&(_, weight)| weight.max(0.0)).sum();
+        let mut pick = self.rng.next_unit() * total;
+        // Falls back to the last choice if rounding leaves a little over.
+        let mut duration = self.durations.last().map_or(Division::note(4), |&(division, _)| division);
+        for &(division, weight) in &self.durations {
+            if pick < weight.max(0.0) {
+                duration = division;
+                break;
+            }
+            pick -= weight.max(0.0);
+        }
+        (self.scale.degree(degree), duration)
+    }
+}
+
+impl Source for RandomMelody {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        if self.release_at.is_some_and(|release_at| self.samples >= release_at) {
+            self.release_at = None;
+            self.envelope.gate_off();
+        }
+        if self.samples >= self.next_start {
+            let (note, duration) = self.next_note();
+            let start = self.samples;
+            self.beats += duration.beats as f64;
+            self.next_start = (self.beats * 60.0 / self.bpm as f64 * elapsed.rate as f64).ceil() as u64;
+            let held = (self.next_start.saturating_sub(start) as f64 * self.gate as f64).round() as u64;
+            self.release_at = Some(start + held);
+            self.note = Some(note);
+            self.osc.hz = note.hz();
+            self.envelope.gate_on();
+        }
+        self.samples += elapsed.count;
+        self.osc.update(elapsed);
+        self.envelope.update(elapsed);
+    }
+
+    fn sample(&self) -> f32 {
+        self.osc.sample() * self.envelope.sample()
+    }
+
+    fn reset(&mut self) {
+        self.rng = XorShift::new(self.seed);
+        self.samples = 0;
+        self.beats = 0.0;
+        self.next_start = 0;
+        self.release_at = None;
+        self.note = None;
+        self.osc = sine(0.0);
+        self.envelope.reset();
+    }
+}
+
+// Durations are (division, weight) pairs, e.g. mostly eighths with a few quarters. With none,
+// every note is a quarter. Each note is held for 90% of its length, see with_gate().
+pub fn random_melody(scale: Scale, durations: Vec<(Division, f32)>, bpm: f32) -> RandomMelody {
+    RandomMelody {
+        scale,
+        degrees: 0..=7,
+        durations,
+        bpm,
+        gate: 0.9,
+        seed: 1,
+        rng: XorShift::new(1),
+        samples: 0,
+        beats: 0.0,
+        next_start: 0,
+        release_at: None,
+        note: None,
+        osc: sine(0.0),
+        envelope: adsr_gated(0.005, 0.06, 0.7, 0.14),
+    }
+}
+
+#[derive(Debug)]
+pub enum MixBusError {
+    NoSuchBus(String),
+    DuplicateBus(String),
+    // Routing from the first bus to the second would feed it back into itself.
+    Cycle(String, String),
+}
+
+impl std::fmt::Display for MixBusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSuchBus(name) => write!(f, "no bus named {name:?}"),
+            Self::DuplicateBus(name) => write!(f, "a bus named {name:?} already exists"),
+            Self::Cycle(from, to) => write!(f, "routing {from:?} to {to:?} would create a feedback loop"),
+        }
+    }
+}
+
+impl std::error::Error for MixBusError {}
+
+struct Bus {
+    name: String,
+    gain: f32,
+    sources: Mixer,
+    // What the chain reads: the bus's own sources, plus anything routed into it.
+    input: Param,
+    chain: Box<dyn Source<Sample=f32> + Send>,
+    // None for the master.
+    output: Option<usize>,
+    received: f32,
+}
+
+// Named buses, each summing its sources through an effect chain and a gain into either the
+// master or another bus. Routing can't loop. An empty bus still runs its chain on silence, so
+// e.g. a reverb tail carries on after its sources are removed.
+pub struct MixBus {
+    buses: Vec<Bus>,
+    // Bus indexes with every bus after all the buses routed into it.
+    order: Vec<usize>,
+    master_gain: f32,
+    output: f32,
+}
+
+impl MixBus {
+    pub fn add_bus(&mut self, name: &str) -> Result<(), MixBusError> {
+        self.add_bus_with(name, |input| input)
+    }
+
+    // chain builds the bus's effects from its input, e.g. `|input| input.reverb(0.8, 0.3, 0.4)`.
+    pub fn add_bus_with<S>(&mut self, name: &str, chain: impl FnOnce(Param) -> S) -> Result<(), MixBusError>
+        where S: Source<Sample=f32> + Send + 'static,
+    {
+        if self.find(name).is_ok() {
+            return Err(MixBusError::DuplicateBus(name.to_string()));
+        }
+        let input = Param::new(0.0);
+        self.buses.push(Bus {
+            name: name.to_string(),
+            gain: 1.0,
+            sources: mixer(),
+            chain: Box::new(chain(input.clone())),
+            input,
+            output: None,
+            received: 0.0,
+        });
+        self.sort();
+        Ok(())
+    }
+
+    pub fn add_source(&mut self, bus: &str, source: impl Source<Sample=f32> + Send + 'static) -> Result<(), MixBusError> {
+        let index = self.find(bus)?;
+        self.buses[index].sources.add_voice(source);
+        Ok(())
+    }
+
+    pub fn set_gain(&mut self, bus: &str, gain: f32) -> Result<(), MixBusError> {
+        let index = self.find(bus)?;
+        self.buses[index].gain = gain;
+        Ok(())
+    }
+
+    pub fn set_master_gain(&mut self, gain: f32) {
+        self.master_gain = gain;
+    }
+
+    // Sends from's output into to instead of the master.
+    pub fn route(&mut self, from: &str, to: &str) -> Result<(), MixBusError> {
+        let from_index = self.find(from)?;
+        let to_index = self.find(to)?;
+        // Each bus has one output, so it's a loop if following outputs from to leads back.
+        let mut next = Some(to_index);
+        while let Some(index) = next {
+            if index == from_index {
+                return Err(MixBusError::Cycle(from.to_string(), to.to_string()));
+            }
+            next = self.buses[index].output;
+        }
+        self.buses[from_index].output = Some(to_index);
+        self.sort();
+        Ok(())
+    }
+
+    pub fn route_to_master(&mut self, bus: &str) -> Result<(), MixBusError> {
+        let index = self.find(bus)?;
+        self.buses[index].output = None;
+        self.sort();
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Result<usize, MixBusError> {
+        self.buses.iter()
+            .position(|bus| bus.name == name)
+            .ok_or_else(|| MixBusError::NoSuchBus(name.to_string()))
+    }
+
+    // Buses further from the master go first, so their output is in before it's needed.
+    fn sort(&mut self) {
+        let depth = |mut index: usize| {
+            let mut depth = 0;
+            while let Some(output) = self.buses[index].output {
+                index = output;
+                depth += 1;
+            }
+            depth
+        };
+        let mut order: Vec<usize> = (0..self.buses.len()).collect();
+        order.sort_by_key(|&index| std::cmp::Reverse(depth(index)));
+        self.order = order;
+    }
+}
+
+impl Source for MixBus {
+    type Sample = f32;
+
+    fn update(&mut self, elapsed: SampleTime) {
+        for bus in &mut self.buses {
+            bus.received = 0.0;
+        }
+        let mut master = 0.0;
+        for &index in &self.order {
+            let bus = &mut self.buses[index];
+            bus.sources.update(elapsed);
+            bus.input.set(bus.sources.sample() + bus.received);
+            bus.chain.update(elapsed);
+            let output = bus.chain.sample() * bus.gain;
+            match bus.output {
+                Some(to) => self.buses[to].received += output,
+                None => master += output,
+            }
+        }
+        self.output = master * self.master_gain;
+    }
+
+    // Silent with no buses.
+    fn sample(&self) -> f32 {
+        self.output
+    }
+
+    fn reset(&mut self) {
+        for bus in &mut self.buses {
+            bus.sources.reset();
+            bus.input.set(0.0);
+            bus.chain.reset();
+            bus.received = 0.0;
+        }
+        self.output = 0.0;
+    }
+}
+
+pub fn mix_bus() -> MixBus {
+    MixBus { buses: Vec::new(), order: Vec::new(), master_gain: 1.0, output: 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 1000;
+
+    // Steps one sample at a time, like the stream callback.
+    fn render<S: Source>(source: &mut S, rate: u32, frames: usize) -> Vec<S::Sample> {
+        (0..frames)
+            .map(|_| {
+                source.update(SampleTime { count: 1, rate });
+                source.sample()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn square_flips_at_duty_boundary() {
+        // 10 samples per period, so high for the first quarter: phases 0.1 and 0.2, then 0.0
+        // again at the start of the next period.
+        let out = render(&mut square(100.0).with_duty(0.25), RATE, 10);
+        assert_eq!(out, [1.0, 1.0, -1.0, -1.0, -1.0, -1.0, -1.0, -1.0, -1.0, 1.0]);
+    }
+
+    fn min_max(samples: &[f32]) -> (f32, f32) {
+        samples.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &s| (lo.min(s), hi.max(s)))
+    }
+
+    #[test]
+    fn saw_and_triangle_span_their_range_each_period() {
+        // 100 samples per period, phase stepping by 0.01, so both get within a step of their
+        // extremes.
+        for out in [render(&mut saw(10.0), RATE, 100), render(&mut triangle(10.0), RATE, 100)] {
+            let (lo, hi) = min_max(&out);
+            assert!((-1.0..-0.97).contains(&lo), "{lo}");
+            assert!((0.97..=1.0).contains(&hi), "{hi}");
+        }
+    }
+
+    #[test]
+    fn saw_and_triangle_take_vibrato() {
+        let (lo, hi) = min_max(&render(&mut saw(10.0).vibrato(2.0, 100.0), RATE, 1000));
+        assert!(lo >= -1.0 && hi < 1.0);
+        let (lo, hi) = min_max(&render(&mut triangle(10.0).vibrato(2.0, 100.0), RATE, 1000));
+        assert!(lo >= -1.0 && hi <= 1.0);
+    }
+
+    #[test]
+    fn sample_time_ordering_survives_large_counts() {
+        // About 23 hours in, where count * rate no longer fits in 32 bits.
+        let a = SampleTime { count: 4_000_000_000, rate: 48000 };
+        let b = SampleTime { count: 3_700_000_000, rate: 44100 };
+        assert!(a < b);
+        assert!(b > a);
+        assert_eq!(a, SampleTime { count: 8_000_000_000, rate: 96000 });
+    }
+
+    fn voices() -> impl Source<Sample=f32> {
+        sine(220.0).wrap() * sine(3.0) + sine(330.0).wrap() * 0.5 + sine(440.0).wrap() * sine(5.0)
+    }
+
+    #[test]
+    fn fill_matches_per_sample_rendering() {
+        let mut block = vec![0.0; 1000];
+        voices().fill(&mut block, RATE);
+        assert_eq!(block, render(&mut voices(), RATE, 1000));
+    }
+
+    // Sum of squared first differences, which weighs high frequencies far more than low.
+    fn high_frequency_energy(samples: &[f32]) -> f32 {
+        samples.windows(2).map(|pair| (pair[1] - pair[0]).powi(2)).sum()
+    }
+
+    #[test]
+    fn low_pass_attenuates_high_frequencies() {
+        let dry = render(&mut square(5000.0), 48000, 48000);
+        let wet = render(&mut square(5000.0).low_pass(500.0), 48000, 48000);
+        assert!(high_frequency_energy(&wet) < high_frequency_energy(&dry) / 100.0);
+    }
+
+    // Peak level once any start up transient has settled.
+    fn settled_peak(samples: &[f32]) -> f32 {
+        samples[samples.len() / 2..].iter().fold(0f32, |peak, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn band_pass_peaks_at_its_center() {
+        let response = |hz: f32| settled_peak(&render(&mut sine(hz).band_pass(1000.0, 2.0), 48000, 9600));
+        let sweep: Vec<f32> = [250.0, 500.0, 1000.0, 2000.0, 4000.0].map(response).into();
+        assert!((sweep[2] - 1.0).abs() < 0.05, "{sweep:?}");
+        assert!(sweep.iter().all(|&gain| gain <= sweep[2]), "{sweep:?}");
+        assert!(sweep[0] < 0.2 && sweep[4] < 0.2, "{sweep:?}");
+    }
+
+    #[test]
+    fn high_pass_clamps_q() {
+        // A Q of 0 would divide by zero without the clamp.
+        let out = render(&mut square(3000.0).high_pass(1000.0, 0.0), 48000, 1000);
+        assert!(out.iter().all(|s| s.is_finite() && s.abs() < 10.0));
+        // Rebuilding gives a fresh filter state.
+        assert_eq!(render(&mut square(3000.0).high_pass(1000.0, 0.0), 48000, 1000), out);
+    }
+
+    // A single sample of 1.0, then silence.
+    struct Impulse {
+        samples: u64,
+    }
+
+    fn impulse() -> Impulse {
+        Impulse { samples: 0 }
+    }
+
+    impl Source for Impulse {
+        type Sample = f32;
+
+        fn update(&mut self, elapsed: SampleTime) {
+            self.samples += elapsed.count;
+        }
+
+        fn sample(&self) -> f32 {
+            if self.samples == 1 { 1.0 } else { 0.0 }
+        }
+    }
+
+    // Indices of the samples that aren't silent.
+    fn nonzero(samples: &[f32]) -> Vec<usize> {
+        samples.iter().enumerate().filter(|(_, s)| s.abs() > 1e-6).map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn echo_repeats_at_the_delay() {
+        let out = render(&mut impulse().echo(0.01, 0.5), RATE, 35);
+        assert_eq!(nonzero(&out), [0, 10, 20, 30]);
+        assert_eq!([out[0], out[10], out[20], out[30]], [1.0, 0.5, 0.25, 0.125]);
+    }
+
+    #[test]
+    fn echo_clamps_runaway_feedback() {
+        let out = render(&mut impulse().echo(0.01, 2.0), RATE, 1000);
+        assert!(out.iter().all(|s| s.abs() <= 1.0));
+        assert!(out[990] > 0.0 && out[990] < out[980]);
+    }
+
+    #[test]
+    fn seeded_noise_is_reproducible() {
+        let a = render(&mut noise_seeded(42), RATE, 1000);
+        assert_eq!(a, render(&mut noise_seeded(42), RATE, 1000));
+        assert_ne!(a, render(&mut noise_seeded(43), RATE, 1000));
+        assert!(a.iter().all(|s| (-1.0..=1.0).contains(s)));
+        assert_eq!(render(&mut noise_seeded(42).pink(), RATE, 1000), render(&mut noise_seeded(42).pink(), RATE, 1000));
+    }
+
+    #[test]
+    fn pink_noise_has_less_high_frequency_energy() {
+        let white = render(&mut noise_seeded(7), 48000, 48000);
+        let pink = render(&mut noise_seeded(7).pink(), 48000, 48000);
+        let energy = |s: &[f32]| s.iter().map(|s| s * s).sum::<f32>();
+        // Compared relative to total energy, so the overall level doesn't matter.
+        assert!(high_frequency_energy(&pink) / energy(&pink) < high_frequency_energy(&white) / energy(&white) / 4.0);
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32], tolerance: f32) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?} vs {expected:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() <= tolerance, "{actual:?} vs {expected:?}");
+        }
+    }
+
+    #[test]
+    fn pan_is_constant_power() {
+        let gains = |position: f32| render(&mut 1.0.pan(position), RATE, 1)[0];
+        assert_close(&gains(0.0), &[std::f32::consts::FRAC_1_SQRT_2; 2], 1e-6);
+        assert_close(&gains(-1.0), &[1.0, 0.0], 1e-6);
+        assert_close(&gains(1.0), &[0.0, 1.0], 1e-6);
+    }
+
+    #[test]
+    fn stereo_frames_fit_any_channel_count() {
+        let interleave = |channels: usize| {
+            let mut buf = vec![0.0; channels * 2];
+            render_interleaved(&mut 0.5.pan(-1.0), &mut buf, channels, RATE);
+            buf
+        };
+        assert_close(&interleave(2), &[0.5, 0.0, 0.5, 0.0], 1e-6);
+        // Extra channels duplicate the last, missing ones are dropped.
+        assert_close(&interleave(3), &[0.5, 0.0, 0.0, 0.5, 0.0, 0.0], 1e-6);
+        assert_close(&interleave(1), &[0.5, 0.5], 1e-6);
+
+        // Mono fills every channel.
+        let mut buf = [0.0; 4];
+        render_interleaved(&mut 0.25, &mut buf, 2, RATE);
+        assert_eq!(buf, [0.25; 4]);
+    }
+
+    #[test]
+    fn vibrato_of_an_octave_doubles_the_frequency_at_its_peak() {
+        // The carrier frequency times the vibrato ratio, as Sine::vibrato uses it.
+        let hz = render(&mut (440.0.wrap() * vibrato(1.0, 1200.0)), RATE, 1000);
+        let (lo, hi) = min_max(&hz);
+        assert!((hi - 880.0).abs() < 0.01, "{hi}");
+        assert!((lo - 220.0).abs() < 0.01, "{lo}");
+        // The sine LFO peaks a quarter of the way through.
+        assert!((hz[249] - 880.0).abs() < 0.01, "{}", hz[249]);
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn wav_has_exactly_duration_times_rate_frames() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, sine(440.0), 8000, std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(&wav, 4) as usize, wav.len() - 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u16_at(&wav, 20), 1);
+        assert_eq!(u16_at(&wav, 22), 1);
+        assert_eq!(u32_at(&wav, 24), 8000);
+        assert_eq!(u32_at(&wav, 28), 16000);
+        assert_eq!(u16_at(&wav, 32), 2);
+        assert_eq!(u16_at(&wav, 34), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(&wav, 40), 16000);
+        assert_eq!(wav.len(), 44 + 8000 * 2);
+    }
+
+    #[test]
+    fn wav_too_long_for_riff_is_an_error() {
+        // Over 4GB of 16 bit stereo.
+        let mut wav = Vec::new();
+        let error = write_wav(&mut wav, 0.0.pan(0.0), 48000, std::time::Duration::from_secs(30000)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(wav.is_empty());
+    }
+
+    #[test]
+    fn rendered_adsr_reaches_its_sustain_level() {
+        let out = render_to_buffer(adsr(0.0..2.0, 0.1, 0.1, 0.6, 0.1), RATE, 1000);
+        assert_eq!(out.len(), 1000);
+        assert!((min_max(&out).1 - 1.0).abs() < 1e-3);
+        assert!(out[300..].iter().all(|&level| (level - 0.6).abs() < 1e-4), "{}", out[300]);
+    }
+
+    #[test]
+    fn parses_note_names() {
+        let note = |s: &str| s.parse::<Note>().unwrap().0;
+        assert_eq!(note("A4"), Key::A.note(4).0);
+        assert_eq!(note("C#5"), Key::CSharp.note(5).0);
+        // Flats are the enharmonic sharp, crossing the octave for Cb.
+        assert_eq!(note("Gb3"), Key::FSharp.note(3).0);
+        assert_eq!(note("Cb4"), Key::B.note(3).0);
+        // MIDI note 0, 69 semitones below A4.
+        assert_eq!(note("C-1"), -69);
+    }
+
+    #[test]
+    fn rejects_invalid_note_names() {
+        let error = |s: &str| s.parse::<Note>().unwrap_err();
+        assert!(matches!(error(""), ParseNoteError::Empty));
+        assert!(matches!(error("H9"), ParseNoteError::InvalidKey('H')));
+        assert!(matches!(error("A"), ParseNoteError::MissingOctave));
+        assert!(matches!(error("A#"), ParseNoteError::MissingOctave));
+        assert!(matches!(error("Ax"), ParseNoteError::InvalidOctave(_)));
+        assert!(matches!(error("A10"), ParseNoteError::OctaveOutOfRange(10)));
+        assert!(matches!(error("A-2"), ParseNoteError::OctaveOutOfRange(-2)));
+        assert_eq!(error("A10").to_string(), "octave 10 out of range -1..=9");
+    }
+
+    #[test]
+    fn midi_notes_line_up_with_hz() {
+        assert_eq!(Note::from_midi(69).0, Key::A.note(4).0);
+        assert_eq!(Note::from_midi(69).0, 0);
+        assert_eq!(Note::from_midi(69).hz(), 440.0);
+        assert_eq!(Note::from_midi(60).0, Key::C.note(4).0);
+        assert_eq!(Note::from_midi(60).0, -9);
+        assert!((Note::from_midi(60).hz() - 261.6256).abs() < 1e-3);
+        assert_eq!(Key::C.note(4).to_midi(), 60);
+        // Out of range values clamp.
+        assert_eq!(Note::from_midi(200).0, Note::from_midi(127).0);
+        assert_eq!(Key::C.note(-3).to_midi(), 0);
+    }
+
+    #[test]
+    fn alternate_tunings() {
+        let a432 = Tuning { reference_hz: 432.0, temperament: Temperament::Equal };
+        for note in [Key::A.note(4), Key::C.note(3), Key::FSharp.note(6)] {
+            assert!((note.hz_in(&a432) / note.hz() - 432.0 / 440.0).abs() < 1e-6);
+        }
+        assert_eq!(Key::A.note(4).hz_in(&Tuning::A440), Key::A.note(4).hz());
+
+        let just = Tuning { reference_hz: 440.0, temperament: Temperament::Just { tonic: Key::C.note(4) } };
+        let fifth = Key::G.note(4).hz_in(&just) / Key::C.note(4).hz_in(&just);
+        assert!((fifth - 1.5).abs() < 1e-6, "{fifth}");
+        // Tonics keep their equal tempered pitch, and octaves stay 2:1.
+        assert_eq!(Key::C.note(4).hz_in(&just), Key::C.note(4).hz());
+        assert!((Key::G.note(5).hz_in(&just) / Key::G.note(4).hz_in(&just) - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sub_and_neg() {
+        assert_eq!(render(&mut (0.75.wrap() - 0.25), RATE, 2), [0.5, 0.5]);
+        assert_eq!(render(&mut -0.75.wrap(), RATE, 2), [-0.75, -0.75]);
+        // Unlike Add, the order matters.
+        assert_eq!(render(&mut (0.25.wrap() - 0.75), RATE, 1), [-0.5]);
+    }
+
+    #[test]
+    fn gain_works_on_either_side() {
+        let left = render(&mut (0.5 * sine(100.0).wrap()), RATE, 20);
+        let right = render(&mut (sine(100.0).wrap() * 0.5), RATE, 20);
+        assert_eq!(left, right);
+        assert_eq!(render(&mut sine(100.0).gain(0.5), RATE, 20), right);
+        // Gain can itself be a source.
+        assert_eq!(render(&mut 0.5.gain(sine(100.0)), RATE, 20), right);
+    }
+
+    #[test]
+    fn clamp_range_limits_to_its_bounds() {
+        let clamped = |min: f32, max: f32| render(&mut sine(100.0).clamp_range(min, max), RATE, 10);
+        let (lo, hi) = min_max(&clamped(-0.5, 0.25));
+        assert_eq!((lo, hi), (-0.5, 0.25));
+        // Swapped and NaN bounds don't panic.
+        assert_eq!(clamped(0.25, -0.5), clamped(-0.5, 0.25));
+        assert_eq!(min_max(&clamped(f32::NAN, 0.25)).1, 0.25);
+        assert_eq!(clamped(f32::NAN, f32::NAN), render(&mut sine(100.0), RATE, 10));
+    }
+
+    #[test]
+    fn tanh_saturate_is_monotonic_and_nearly_linear_when_quiet() {
+        let shaped = |x: f32| render(&mut x.tanh_saturate(), RATE, 1)[0];
+        assert_eq!(shaped(0.0), 0.0);
+        assert!((shaped(0.01) - 0.01).abs() < 1e-6);
+        let curve: Vec<f32> = (-40..=40).map(|x| shaped(x as f32 / 10.0)).collect();
+        assert!(curve.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(curve.iter().all(|y| y.abs() < 1.0));
+    }
+
+    #[test]
+    fn mixer_sums_its_voices() {
+        let mut mix = mixer();
+        assert_eq!(render(&mut mix, RATE, 1), [0.0]);
+        mix.add_voice(0.125);
+        mix.add_voice(0.25);
+        mix.add_voice(0.5);
+        assert_eq!(mix.len(), 3);
+        assert_eq!(render(&mut mix, RATE, 2), [0.875, 0.875]);
+        assert_eq!(mix.remove_voice(1).sample(), 0.25);
+        assert_eq!(render(&mut mix, RATE, 1), [0.625]);
+    }
+
+    fn voice_notes(synth: &Synth) -> Vec<Note> {
+        let mut notes: Vec<_> = synth.voices.iter().map(|voice| voice.note).collect();
+        notes.sort_by_key(|note| note.0);
+        notes
+    }
+
+    #[test]
+    fn synth_steals_the_oldest_voice() {
+        let (c, d, e) = (Key::C.note(4), Key::D.note(4), Key::E.note(4));
+        let mut synth = synth(2, 0.01, 0.01, 0.5, 0.01);
+        synth.note_on(c);
+        synth.note_on(d);
+        synth.note_on(e);
+        assert_eq!(voice_notes(&synth), [d, e]);
+
+        // Releasing a note that isn't playing changes nothing.
+        synth.note_off(c);
+        assert!(synth.voices.iter().all(|voice| voice.is_held()));
+
+        // Released voices are freed once finished, rather than stealing the oldest.
+        synth.note_off(e);
+        render(&mut synth, RATE, 100);
+        synth.note_on(c);
+        assert_eq!(voice_notes(&synth), [c, d]);
+        assert!(render(&mut synth, RATE, 100).iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn gated_adsr_follows_its_gate() {
+        let mut envelope = adsr_gated(0.1, 0.1, 0.5, 0.1);
+        assert!(render(&mut envelope, RATE, 100).iter().all(|&level| level == 0.0));
+
+        envelope.gate_on();
+        let on = render(&mut envelope, RATE, 300);
+        assert!(on[..100].windows(2).all(|pair| pair[0] < pair[1]));
+        assert!((on[99] - 1.0).abs() < 1e-3);
+        assert!(on[200..].iter().all(|&level| (level - 0.5).abs() < 1e-4));
+
+        envelope.gate_off();
+        let off = render(&mut envelope, RATE, 200);
+        assert!(off[..100].windows(2).all(|pair| pair[0] > pair[1]));
+        assert!(off[100..].iter().all(|&level| level == 0.0));
+    }
+
+    #[test]
+    fn adsr_starts_on_the_first_sample_of_its_range() {
+        // An instant attack is at full level on the very first sample.
+        let out = render(&mut adsr(0.0..1.0, 0.0, 0.1, 0.5, 0.1), RATE, 3);
+        assert_eq!(out[0], 1.0);
+        let out = render(&mut adsr(0.0..1.0, 0.01, 0.1, 0.5, 0.1), RATE, 3);
+        assert_close(&out, &[0.1, 0.2, 0.3], 1e-6);
+
+        // Releases line up with the end of the range the same way.
+        let out = render(&mut adsr(0.0..0.005, 0.0, 0.0, 1.0, 0.0), RATE, 7);
+        assert_eq!(out, [1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn curves_change_the_path_but_not_the_duration() {
+        let attack = |curve: Curve| render(&mut adsr(0.0..1.0, 0.1, 0.1, 0.5, 0.1).with_curve(curve), RATE, 100);
+        let (linear, exponential, logarithmic) = (attack(Curve::Linear), attack(Curve::Exponential), attack(Curve::Logarithmic));
+        assert!((linear[49] - 0.5).abs() < 1e-4);
+        assert!(exponential[49] > 0.9, "{}", exponential[49]);
+        assert!(logarithmic[49] < 0.1, "{}", logarithmic[49]);
+        for levels in [linear, exponential, logarithmic] {
+            assert!((levels[99] - 1.0).abs() < 1e-4);
+        }
+        assert!(matches!(adsr(0.0..1.0, 0.1, 0.1, 0.5, 0.1).curve, Curve::Linear));
+    }
+
+    #[test]
+    fn lfo_completes_a_cycle_per_period() {
+        let out = render(&mut lfo(1.0, Waveform::Saw), RATE, 2500);
+        let wraps: Vec<usize> = (1..out.len()).filter(|&i| out[i] < out[i - 1]).collect();
+        assert_eq!(wraps.len(), 2, "{wraps:?}");
+        assert!(wraps[0].abs_diff(999) <= 1 && wraps[1].abs_diff(1999) <= 1, "{wraps:?}");
+
+        let (lo, hi) = min_max(&render(&mut lfo(1.0, Waveform::Sine).unipolar(), RATE, 1000));
+        assert!(lo.abs() < 1e-4 && (hi - 1.0).abs() < 1e-4);
+        let (lo, hi) = min_max(&render(&mut lfo(1.0, Waveform::Square).with_range(0.2, 0.8), RATE, 1000));
+        assert_eq!((lo, hi), (0.2, 0.8));
+    }
+
+    #[test]
+    fn tremolo_dips_at_its_rate() {
+        assert!(render(&mut 0.5.tremolo(5.0, 0.0), RATE, 1000).iter().all(|&s| s == 0.5));
+
+        // Full gain at the start of each period, and down by depth half way through.
+        let out = render(&mut 1.0.tremolo(2.0, 0.5), RATE, 1000);
+        assert!((out[0] - 1.0).abs() < 1e-3);
+        assert!((out[249] - 0.5).abs() < 1e-3, "{}", out[249]);
+        assert!((out[499] - 1.0).abs() < 1e-3, "{}", out[499]);
+        assert!((out[749] - 0.5).abs() < 1e-3, "{}", out[749]);
+        assert!((min_max(&out).0 - 0.5).abs() < 1e-3);
+    }
+
+    fn silent(samples: &[f32]) -> bool {
+        samples.iter().all(|s| s.abs() < 1e-3)
+    }
+
+    #[test]
+    fn sequence_plays_each_note_in_its_window() {
+        // One beat a second.
+        let mut song = sequence(60.0, synth(4, 0.01, 0.01, 0.5, 0.01))
+            .note(2.0, 1.0, Key::E.note(4))
+            .note(0.0, 1.0, Key::C.note(4));
+        let out = render(&mut song, RATE, 4000);
+        for window in [0..1000, 2000..3000] {
+            assert!(out[window.clone()].chunks(100).all(|chunk| !silent(chunk)), "{window:?}");
+        }
+        // After the 10ms release tails.
+        assert!(silent(&out[1011..2000]));
+        assert!(silent(&out[3011..]));
+    }
+
+    #[test]
+    fn overlapping_notes_mix() {
+        let single = render(&mut sequence(60.0, synth(4, 0.0, 0.0, 1.0, 0.0)).note(0.0, 2.0, Key::A.note(4)), RATE, 2000);
+        let mut song = sequence(60.0, synth(4, 0.0, 0.0, 1.0, 0.0))
+            .note(0.0, 2.0, Key::A.note(4))
+            .note(1.0, 2.0, Key::A.note(5));
+        let out = render(&mut song, RATE, 2000);
+        assert_eq!(out[..1000], single[..1000]);
+        let octave = render(&mut Key::A.note(5).sine(), RATE, 1000);
+        for i in 1000..2000 {
+            assert!((out[i] - single[i] - octave[i - 1000]).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn note_off_releases_the_oldest_voice_of_the_note() {
+        let note = Key::C.note(4);
+        let mut synth = synth(4, 0.0, 0.0, 1.0, 0.0);
+        synth.note_on(note);
+        render(&mut synth, RATE, 1);
+        synth.note_on(note);
+        synth.note_off(note);
+        let held: Vec<u64> = synth.voices.iter().filter(|voice| voice.is_held()).map(|voice| voice.age).collect();
+        assert_eq!(held, [1]);
+        synth.note_off(note);
+        assert!(synth.voices.iter().all(|voice| !voice.is_held()));
+    }
+
+    #[test]
+    fn sines_half_a_cycle_apart_cancel() {
+        let out = render(&mut (sine(440.0).wrap() + sine(440.0).phase_offset(0.5)), 48000, 1000);
+        assert!(out.iter().all(|s| s.abs() < 1e-4));
+        // The offset holds under modulation too, since it's applied when sampling.
+        let out = render(&mut (sine(440.0).vibrato(3.0, 50.0).wrap() + sine(440.0).vibrato(3.0, 50.0).phase_offset(0.5)), 48000, 1000);
+        assert!(out.iter().all(|s| s.abs() < 1e-4));
+    }
+
+    #[test]
+    fn hard_sync_restarts_on_the_master_cycle() {
+        // The master wraps every 10 samples, so the slave's cycle repeats every 10 too.
+        let out = render(&mut sine(130.0).hard_sync(100.0), RATE, 40);
+        assert_close(&out[10..20], &out[20..30], 1e-3);
+        assert_close(&out[20..30], &out[30..40], 1e-3);
+        assert_ne!(render(&mut sine(130.0), RATE, 40)[20..30], out[20..30]);
+    }
+
+    #[test]
+    fn dc_block_removes_offsets() {
+        // No spurious step from the initial state.
+        assert!(render(&mut 0.5.dc_block(), 48000, 100).iter().all(|&s| s == 0.0));
+
+        // A step up to 0.5 comes through, then decays away.
+        let step = adsr(0.01..10.0, 0.0, 0.0, 1.0, 0.0).gain(0.5);
+        let out = render(&mut step.dc_block(), 48000, 48000);
+        assert!(out[..480].iter().all(|&s| s == 0.0));
+        assert!((out[480] - 0.5).abs() < 1e-6);
+        assert!(out[481..].windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(out[47999] < 1e-3, "{}", out[47999]);
+    }
+
+    #[test]
+    fn fades_ramp_over_their_duration() {
+        let out = render(&mut 1.0.fade_in(0.1), RATE, 200);
+        assert_eq!(out[0], 0.0);
+        assert!(out[..100].windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(out[100..].iter().all(|&s| s == 1.0));
+        // The same times in samples at another rate.
+        let out = render(&mut 1.0.fade_in(0.1), 48000, 9600);
+        assert!(out[4799] < 1.0 && out[4800] == 1.0);
+
+        let out = render(&mut 1.0.fade_out_at(0.1, 0.1), RATE, 300);
+        assert!(out[..101].iter().all(|&s| s == 1.0));
+        assert!(out[101..200].windows(2).all(|pair| pair[0] > pair[1]));
+        assert!(out[200..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn konst_holds_its_value() {
+        assert_eq!(konst(0.25).sample(), 0.25);
+        let mut stereo = konst([0.5, 0.25]);
+        assert_eq!(render(&mut stereo, RATE, 2), [[0.5, 0.25]; 2]);
+        let mut buf = [0.0; 4];
+        render_interleaved(&mut stereo, &mut buf, 2, RATE);
+        assert_eq!(buf, [0.5, 0.25, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn sample_player_resamples_with_interpolation() {
+        let ramp = vec![0.0, 1.0, 2.0, 3.0];
+        // Native rate 500 played at 1000, so half a buffer sample per output sample.
+        let out = render(&mut play_sample(ramp.clone(), 500), RATE, 9);
+        assert_eq!(out, [0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 3.0, 0.0]);
+
+        let out = render(&mut play_sample(ramp.clone(), 2000), RATE, 4);
+        assert_eq!(out, [0.0, 2.0, 0.0, 0.0]);
+
+        // Looping interpolates from the end back to the start.
+        let out = render(&mut play_sample(ramp, 500).looping(), RATE, 10);
+        assert_eq!(out, [0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 1.5, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn sine_wavetable_matches_sine() {
+        let table = render(&mut wavetable(sine_table(2048), 440.0), 48000, 4800);
+        assert_close(&table, &render(&mut sine(440.0), 48000, 4800), 1e-4);
+    }
+
+    #[test]
+    fn unison_voices_beat_at_their_detuning() {
+        assert_eq!(render(&mut sine(100.0).unison(1, 50.0), RATE, 100), render(&mut sine(100.0), RATE, 100));
+
+        // The outer voices are at 101Hz and about 99Hz, so the mix beats about once a second:
+        // in phase at whole seconds, and the outer two opposing the middle one in between.
+        let cents = 1200.0 * 1.01f32.log2();
+        let out = render(&mut sine(100.0).unison(3, cents), 48000, 52800);
+        // A 20ms window is two periods at 100Hz.
+        let peak = |secs: f32| {
+            let at = (secs * 48000.0) as usize;
+            out[at - 480..at + 480].iter().fold(0f32, |peak, s| peak.max(s.abs()))
+        };
+        assert!(peak(0.02) > 0.95, "{}", peak(0.02));
+        assert!((peak(0.5) - 1.0 / 3.0).abs() < 0.05, "{}", peak(0.5));
+        assert!(peak(1.0) > 0.95, "{}", peak(1.0));
+    }
+
+    #[test]
+    fn glide_approaches_its_target_monotonically() {
+        for mut hz in [glide(220.0, 0.1), glide(220.0, 0.1).exponential()] {
+            render(&mut hz, RATE, 10);
+            hz.set_target(440.0);
+            let out = render(&mut hz, RATE, 150);
+            assert!(out[..100].windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(out[99..].iter().all(|&hz| (hz - 440.0).abs() < 1e-3));
+
+            // Retargeting part way starts from where it got to.
+            hz.set_target(220.0);
+            render(&mut hz, RATE, 50);
+            let from = hz.sample();
+            hz.set_target(330.0);
+            assert!((render(&mut hz, RATE, 1)[0] - from).abs() < 5.0);
+        }
+    }
+
+    // Amplitude of the hz component, by correlating with a sine and cosine at that frequency.
+    // Exact for whole numbers of cycles in samples.
+    fn magnitude_at(samples: &[f32], rate: u32, hz: f32) -> f32 {
+        let (re, im) = samples.iter().enumerate().fold((0.0, 0.0), |(re, im), (i, &s)| {
+            let angle = std::f64::consts::TAU * hz as f64 * i as f64 / rate as f64;
+            (re + s as f64 * angle.cos(), im + s as f64 * angle.sin())
+        });
+        (2.0 * (re * re + im * im).sqrt() / samples.len() as f64) as f32
+    }
+
+    #[test]
+    fn ring_mod_gives_sum_and_difference_frequencies() {
+        let out = render(&mut sine(440.0).ring_mod(sine(100.0)), 48000, 48000);
+        assert!((magnitude_at(&out, 48000, 340.0) - 0.5).abs() < 0.01);
+        assert!((magnitude_at(&out, 48000, 540.0) - 0.5).abs() < 0.01);
+        assert!(magnitude_at(&out, 48000, 440.0) < 0.01);
+        assert!(magnitude_at(&out, 48000, 100.0) < 0.01);
+    }
+
+    #[test]
+    fn bitcrush_quantizes_and_holds() {
+        assert_eq!(render(&mut sine(100.0).bitcrush(32, 1), RATE, 20), render(&mut sine(100.0), RATE, 20));
+
+        let out = render(&mut sine(100.0).bitcrush(1, 1), RATE, 20);
+        let mut levels = out.clone();
+        levels.sort_by(f32::total_cmp);
+        levels.dedup();
+        assert_eq!(levels, [-0.5, 0.5]);
+
+        let out = render(&mut saw(10.0).bitcrush(32, 4), RATE, 20);
+        assert!(out.chunks(4).all(|hold| hold.iter().all(|&s| s == hold[0])));
+        assert_ne!(out[0], out[4]);
+    }
+
+    #[test]
+    fn shapers_are_monotonic_and_keep_silence_silent() {
+        for kind in [ShaperKind::HardClip, ShaperKind::SoftClip, ShaperKind::Arctan, ShaperKind::Cubic] {
+            assert_eq!(kind.shape(0.0), 0.0, "{kind:?}");
+            let curve: Vec<f32> = (-300..=300).map(|x| kind.shape(x as f32 / 100.0)).collect();
+            assert!(curve.windows(2).all(|pair| pair[0] <= pair[1]), "{kind:?}");
+            assert!(curve.iter().all(|y| y.abs() <= 1.0), "{kind:?}");
+            // Odd symmetry, so positive and negative halves distort alike.
+            assert!((kind.shape(0.5) + kind.shape(-0.5)).abs() < 1e-6, "{kind:?}");
+        }
+        // Fixed points where they reach full scale.
+        assert_eq!(ShaperKind::HardClip.shape(1.0), 1.0);
+        assert_eq!(ShaperKind::Cubic.shape(1.0), 1.0);
+        assert_eq!(ShaperKind::Cubic.shape(-1.0), -1.0);
+
+        assert!(render(&mut 0.0.distort(10.0, ShaperKind::SoftClip), RATE, 5).iter().all(|&s| s == 0.0));
+        assert_eq!(render(&mut 0.25.distort(2.0, ShaperKind::HardClip), RATE, 1), [0.5]);
+    }
+
+    #[test]
+    fn chords_stack_intervals_on_the_root() {
+        let c = Key::C.note(4);
+        assert_eq!(Chord::major(c).notes(), [Key::C.note(4), Key::E.note(4), Key::G.note(4)]);
+        assert_eq!(Chord::major(c).notes().iter().map(|note| note.0 - c.0).collect::<Vec<_>>(), [0, 4, 7]);
+        assert_eq!(Chord::minor(c).notes()[1], Key::DSharp.note(4));
+        assert_eq!(Chord::dominant_seventh(c).notes()[3], Key::ASharp.note(4));
+        assert_eq!(Chord::major_seventh(c).notes()[3], Key::B.note(4));
+        assert_eq!(Chord::major(c).invert(1).notes(), [Key::E.note(4), Key::G.note(4), Key::C.note(5)]);
+        assert_eq!(Chord::major(c).invert(3).notes(), Chord::major(Key::C.note(5)).notes());
+    }
+
+    #[test]
+    fn scales_walk_their_mode_across_octaves() {
+        let c = Key::C.note(4);
+        let offsets = |mode: Mode, count: usize| -> Vec<i32> {
+            Scale::new(c, mode).notes().take(count).map(|note| note.0 - c.0).collect()
+        };
+        assert_eq!(offsets(Mode::Major, 7), [0, 2, 4, 5, 7, 9, 11]);
+        assert_eq!(offsets(Mode::Minor, 7), [0, 2, 3, 5, 7, 8, 10]);
+        assert_eq!(offsets(Mode::Dorian, 7), [0, 2, 3, 5, 7, 9, 10]);
+        assert_eq!(offsets(Mode::Major, 10)[7..], [12, 14, 16]);
+
+        let scale = Scale::new(c, Mode::Major);
+        assert_eq!(scale.degree(-1), Key::B.note(3));
+        assert!(scale.contains(Key::E.note(7)));
+        assert!(!scale.contains(Key::DSharp.note(2)));
+    }
+
+    #[test]
+    fn arpeggiator_steps_on_exact_sample_boundaries() {
+        let (c, e, g) = (Key::C.note(4), Key::E.note(4), Key::G.note(4));
+        let mut arp = arpeggiator(&[c, e, g], 10.0, ArpPattern::Up).octaves(2);
+        let notes: Vec<Note> = (0..700)
+            .map(|_| {
+                arp.update(SampleTime { count: 1, rate: RATE });
+                arp.current_note().unwrap()
+            })
+            .collect();
+        let expected = [c, e, g, Key::C.note(5), Key::E.note(5), Key::G.note(5), c];
+        for (step, note) in expected.iter().enumerate() {
+            assert!(notes[step * 100..step * 100 + 100].iter().all(|n| n == note), "step {step}");
+        }
+    }
+
+    #[test]
+    fn arpeggiator_patterns() {
+        let notes = [Key::C.note(4), Key::E.note(4), Key::G.note(4)];
+        let steps = |arp: Arpeggiator| -> Vec<i32> {
+            let mut arp = arp;
+            (0..6)
+                .map(|_| {
+                    render(&mut arp, RATE, 100);
+                    arp.current_note().unwrap().0
+                })
+                .collect()
+        };
+        let [c, e, g] = notes.map(|note| note.0);
+        assert_eq!(steps(arpeggiator(&notes, 10.0, ArpPattern::Down)), [g, e, c, g, e, c]);
+        assert_eq!(steps(arpeggiator(&notes, 10.0, ArpPattern::UpDown)), [c, e, g, e, c, e]);
+        let random = steps(arpeggiator(&notes, 10.0, ArpPattern::Random).seeded(3));
+        assert_eq!(random, steps(arpeggiator(&notes, 10.0, ArpPattern::Random).seeded(3)));
+        assert!(random.iter().all(|note| [c, e, g].contains(note)));
+    }
+
+    #[test]
+    fn equal_power_crossfade_is_about_0_707_each_at_the_middle() {
+        let gains = |mix: f32| {
+            let a = render(&mut 1.0.crossfade(0.0, mix), RATE, 1)[0];
+            let b = render(&mut 0.0.crossfade(1.0, mix), RATE, 1)[0];
+            (a, b)
+        };
+        let (a, b) = gains(0.5);
+        assert!((a - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6 && (a - b).abs() < 1e-6);
+        assert_eq!(gains(0.0), (1.0, 0.0));
+        assert!(gains(1.0).0.abs() < 1e-6 && gains(1.0).1 == 1.0);
+        // Constant power throughout.
+        for mix in [0.1, 0.3, 0.7, 0.9] {
+            let (a, b) = gains(mix);
+            assert!((a * a + b * b - 1.0).abs() < 1e-5);
+        }
+
+        assert_eq!(render(&mut 1.0.crossfade(0.0, 0.25).linear(), RATE, 1), [0.75]);
+    }
+
+    #[test]
+    fn one_shot_finishes_exactly_at_its_duration() {
+        let mut shot = 0.5.one_shot(0.01);
+        let mut finished = Vec::new();
+        let out: Vec<f32> = (0..15)
+            .map(|_| {
+                shot.update(SampleTime { count: 1, rate: RATE });
+                finished.push(shot.finished());
+                shot.sample()
+            })
+            .collect();
+        assert_eq!(out[..10], [0.5; 10]);
+        assert_eq!(out[10..], [0.0; 5]);
+        // Finished with the last audible sample, since everything after it is silent.
+        assert_eq!(finished.iter().position(|&f| f), Some(9));
+    }
+
+    #[test]
+    fn reverb_tail_decays() {
+        let out = render(&mut impulse().reverb(0.7, 0.3, 1.0), 48000, 48000 * 4);
+        let energy = |s: &[f32]| s.iter().map(|s| s * s).sum::<f32>();
+        let seconds: Vec<f32> = out.chunks(48000).map(energy).collect();
+        assert!(seconds[0] > 0.0);
+        assert!(seconds.windows(2).all(|pair| pair[1] < pair[0]), "{seconds:?}");
+        assert!(out.iter().all(|s| s.is_finite() && s.abs() < 1.0));
+
+        // Dry only is the source untouched.
+        assert_eq!(render(&mut noise_seeded(1).reverb(1.0, 0.0, 0.0), 48000, 1000), render(&mut noise_seeded(1), 48000, 1000));
+    }
+
+    #[test]
+    fn compressor_settles_loud_signals_towards_the_threshold() {
+        assert!(render(&mut 0.25.compress(0.5, 4.0, 0.01, 0.1), 48000, 4800).iter().all(|&s| s == 0.25));
+
+        let out = render(&mut 1.0.limit(0.5), 48000, 4800);
+        assert!(out[0] > 0.9);
+        assert!(out[480..].iter().all(|&s| (s - 0.5).abs() < 0.01), "{}", out[480]);
+
+        // 6dB over at 2:1 comes out 3dB over.
+        let out = render(&mut 1.0.compress(0.5, 2.0, 0.01, 0.1), 48000, 48000);
+        assert!((out[47999] - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.01, "{}", out[47999]);
+    }
+
+    #[test]
+    fn map_applies_the_closure_to_each_sample() {
+        assert_eq!(render(&mut 0.25.map(|x| x * 2.0), RATE, 3), [0.5; 3]);
+        // And composes with other combinators.
+        let mut chain = (sine(100.0).wrap() * 0.5).map(|x| x + 1.0).clamp_range(0.0, 1.25);
+        let (lo, hi) = min_max(&render(&mut chain, RATE, 100));
+        assert!(lo >= 0.5 - 1e-6 && hi == 1.25);
+    }
+
+    #[test]
+    fn widen_of_zero_is_mono_and_keeps_the_mid() {
+        let stereo = || sine(100.0).pan(-0.5);
+        for frame in render(&mut stereo().widen(0.0), RATE, 20) {
+            assert_eq!(frame[0], frame[1]);
+        }
+        assert_close(render(&mut stereo().widen(1.0), RATE, 20).as_flattened(), render(&mut stereo(), RATE, 20).as_flattened(), 1e-6);
+        // Exaggerated width still sums to the same mono.
+        let sum = |frames: Vec<[f32; 2]>| frames.iter().map(|[l, r]| l + r).collect::<Vec<_>>();
+        assert_close(&sum(render(&mut stereo().widen(2.0), RATE, 20)), &sum(render(&mut stereo(), RATE, 20)), 1e-6);
+    }
+
+    #[test]
+    fn spectrum_peaks_at_a_sine_frequency() {
+        let mut tap = sine(1000.0).spectrum(1024);
+        let out = render(&mut tap, 48000, 2048);
+        // Audio passes through untouched.
+        assert_eq!(out, render(&mut sine(1000.0), 48000, 2048));
+
+        let magnitudes = tap.magnitudes();
+        assert_eq!(magnitudes.len(), 512);
+        let peak = (0..magnitudes.len()).max_by(|&a, &b| magnitudes[a].total_cmp(&magnitudes[b])).unwrap();
+        // Bins are 46.875Hz wide here.
+        assert!((tap.bin_hz(peak) - 1000.0).abs() <= tap.bin_hz(1) / 2.0, "{}", tap.bin_hz(peak));
+    }
+
+    #[test]
+    fn patches_round_trip_through_text_and_build() {
+        let patch: Patch = "
+            # A plucky bass
+            oscillator = saw
+            hz = 110
+            release = 0.2
+            effect = low_pass 800
+            effect = echo 0.3 0.5
+        ".parse().unwrap();
+        assert_eq!(patch.oscillator, PatchOscillator::Saw);
+        assert_eq!(patch.effects, [PatchEffect::LowPass(800.0), PatchEffect::Echo { delay_secs: 0.3, feedback: 0.5 }]);
+        // Omitted fields keep their defaults.
+        assert_eq!(patch.sustain_level, Patch::default().sustain_level);
+        assert_eq!(patch.to_string().parse::<Patch>().unwrap(), patch);
+
+        let out = render_to_buffer(patch.build(), 48000, 48000);
+        assert!(out.iter().any(|&s| s.abs() > 0.1));
+    }
+
+    #[test]
+    fn bad_patch_lines_name_the_problem() {
+        let error = |text: &str| text.parse::<Patch>().unwrap_err().to_string();
+        assert_eq!(error("hz = 110\ncolour = blue"), "line 2: unknown field \"colour\"");
+        assert_eq!(error("hz = loud"), "line 1: invalid value \"loud\"");
+        assert_eq!(error("oscillator = kazoo"), "line 1: invalid value \"kazoo\"");
+        assert_eq!(error("effect = echo 0.3"), "line 1: invalid value \"echo 0.3\"");
+        assert_eq!(error("sine"), "line 1: expected `field = value`");
+    }
+
+    fn midi_file(tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        // Format 1, 96 ticks per quarter note.
+        bytes.extend([0, 0, 0, 6, 0, 1, 0, tracks.len() as u8, 0, 96]);
+        for track in tracks {
+            bytes.extend(b"MTrk");
+            bytes.extend((track.len() as u32).to_be_bytes());
+            bytes.extend(*track);
+        }
+        bytes
+    }
+
+    #[test]
+    fn midi_files_are_timed_from_ticks_and_tempo() {
+        let bytes = midi_file(&[
+            // 60bpm, so a quarter note is a second.
+            &[0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, 0x00, 0xff, 0x2f, 0x00],
+            &[
+                // A program change to skip, then C4 for a beat, ended with running status.
+                0x00, 0xc0, 0x05,
+                0x00, 0x90, 0x3c, 0x64,
+                0x60, 0x3c, 0x00,
+                // E4 for two beats.
+                0x00, 0x90, 0x40, 0x50,
+                0x81, 0x40, 0x80, 0x40, 0x00,
+                0x00, 0xff, 0x2f, 0x00,
+            ],
+        ]);
+        let file = MidiFile::parse(&bytes).unwrap();
+        let (c, e) = (Key::C.note(4), Key::E.note(4));
+        assert_eq!(file.sample_offsets(RATE).collect::<Vec<_>>(), [
+            (0, MidiEvent::NoteOn { note: c, velocity: 0x64 }),
+            (1000, MidiEvent::NoteOff(c)),
+            (1000, MidiEvent::NoteOn { note: e, velocity: 0x50 }),
+            (3000, MidiEvent::NoteOff(e)),
+        ]);
+
+        let out = render(&mut midi_player(file, synth(4, 0.0, 0.0, 1.0, 0.0)), RATE, 3500);
+        assert!(!silent(&out[..3000]) && silent(&out[3001..]));
+    }
+
+    #[test]
+    fn meta_and_sysex_events_cancel_running_status() {
+        let note_on = [0x00, 0x90, 0x3c, 0x64];
+        for interruption in [&[0x00, 0xff, 0x01, 0x00][..], &[0x00, 0xf0, 0x01, 0xf7]] {
+            let track = [&note_on[..], interruption, &[0x00, 0x3c, 0x00]].concat();
+            assert!(matches!(MidiFile::parse(&midi_file(&[&track])), Err(MidiFileError::MissingStatus)));
+        }
+        assert!(matches!(MidiFile::parse(b"RIFF"), Err(MidiFileError::NotMidi)));
+        assert!(matches!(MidiFile::parse(&midi_file(&[&note_on[..3]])), Err(MidiFileError::Truncated)));
+    }
+
+    #[test]
+    fn midi_input_plays_messages_from_another_thread() {
+        let (sender, mut input) = midi_input(synth(4, 0.0, 0.0, 1.0, 0.0));
+        let c = Key::C.note(4);
+        std::thread::spawn(move || {
+            sender.send_bytes(&[0x90, 0x3c, 100]);
+            // Not a note, so ignored.
+            sender.send_bytes(&[0xc0, 5]);
+        })
+        .join()
+        .unwrap();
+        input.update(SampleTime { count: 1, rate: RATE });
+        assert_eq!(voice_notes(&input.synth), [c]);
+        assert!(input.sample() != 0.0);
+        assert_eq!(MidiEvent::from_bytes(&[0x90, 0x3c, 0]), Some(MidiEvent::NoteOff(c)));
+    }
+
+    #[test]
+    fn velocity_scales_peak_amplitude() {
+        let peak = |curve: Curve, velocity: f32| {
+            let mut synth = synth(1, 0.0, 0.0, 1.0, 0.0).with_velocity_curve(curve);
+            synth.note_on_velocity(Key::A.note(4), velocity);
+            settled_peak(&render(&mut synth, 48000, 4800))
+        };
+        let full = peak(Curve::Linear, 1.0);
+        assert!((peak(Curve::Linear, 0.5) / full - 0.5).abs() < 1e-3);
+        assert!((peak(Curve::Linear, 0.25) / full - 0.25).abs() < 1e-3);
+        for curve in [Curve::Linear, Curve::Exponential, Curve::Logarithmic] {
+            assert_eq!(peak(curve, 0.0), 0.0);
+            let peaks: Vec<f32> = (0..=4).map(|step| peak(curve, step as f32 / 4.0)).collect();
+            assert!(peaks.windows(2).all(|pair| pair[0] < pair[1]), "{curve:?}: {peaks:?}");
+        }
+    }
+
+    #[test]
+    fn transposing_c4_up_a_fifth_gives_g4() {
+        let (c, g) = (Key::C.note(4), Key::G.note(4));
+        assert_eq!(c.transpose(7), g);
+        assert_eq!(g.transpose(-7), c);
+        assert!((c.transpose(12).hz() / c.hz() - 2.0).abs() < 1e-6);
+
+        let fifth = c.hz().transpose(7.0);
+        assert!((fifth.sample() - g.hz()).abs() < 1e-3);
+        assert!((c.hz().transpose(12.0).sample() - 2.0 * c.hz()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn negative_frequencies_run_phase_backwards_continuously() {
+        let mut backwards = sine(-100.0);
+        let mut forwards = sine(100.0);
+        for _ in 0..1000 {
+            backwards.update(SampleTime { count: 1, rate: RATE });
+            forwards.update(SampleTime { count: 1, rate: RATE });
+            assert!((0.0..1.0).contains(&backwards.phase), "{}", backwards.phase);
+            assert!((backwards.sample() + forwards.sample()).abs() < 1e-3);
+        }
+
+        // Vibrato deep enough to swing hz between -200 and 200 never jumps the waveform.
+        let mut vibrato = sine(sine(1.0).wrap() * 200.0);
+        let out = render(&mut vibrato, RATE, 2000);
+        let max_step = std::f32::consts::TAU * 200.0 / RATE as f32;
+        assert!(out.windows(2).all(|pair| (pair[1] - pair[0]).abs() <= max_step + 1e-3));
+    }
+
+    #[test]
+    fn frequencies_above_nyquist_are_clamped_to_it() {
+        let nyquist = RATE as f32 / 2.0;
+        assert_eq!(render(&mut saw(nyquist * 1.4), RATE, 100), render(&mut saw(nyquist), RATE, 100));
+        assert_eq!(render(&mut saw(-nyquist * 3.0), RATE, 100), render(&mut saw(-nyquist), RATE, 100));
+    }
+
+    #[test]
+    fn blep_saw_suppresses_aliasing_at_high_pitches() {
+        const RATE: u32 = 48000;
+        // Harmonics 6 to 10 of 4700Hz fold back below Nyquist at these frequencies, none of
+        // which are harmonics themselves.
+        let aliases = [19800.0, 15100.0, 10400.0, 5700.0, 1000.0];
+        let aliasing = |samples: &[f32]| -> f32 { aliases.iter().map(|&hz| magnitude_at(samples, RATE, hz)).sum() };
+        let naive = render(&mut saw(4700.0), RATE, 4800);
+        let blep = render(&mut blep_saw(4700.0), RATE, 4800);
+        assert!(aliasing(&blep) < aliasing(&naive) * 0.5, "{} vs {}", aliasing(&blep), aliasing(&naive));
+        let fundamental = magnitude_at(&blep, RATE, 4700.0) / magnitude_at(&naive, RATE, 4700.0);
+        assert!((0.8..1.2).contains(&fundamental), "{fundamental}");
+
+        // Only the sample either side of each reset differs at low pitches.
+        let naive = render(&mut saw(50.0), RATE, 4800);
+        let blep = render(&mut blep_saw(50.0), RATE, 4800);
+        let differing = naive.iter().zip(&blep).filter(|(a, b)| (*a - *b).abs() > 1e-4).count();
+        assert!(differing <= 2 * 5 + 1, "{differing}");
+    }
+
+    #[test]
+    fn chorus_differs_between_channels() {
+        const RATE: u32 = 48000;
+        let out = render(&mut sine(440.0).chorus(1.5, 0.5, 1.0, 3), RATE, 9600);
+        let difference: f32 = out[4800..].iter().map(|[left, right]| (left - right).abs()).sum::<f32>() / 4800.0;
+        assert!(difference > 0.05, "{difference}");
+
+        let dry = render(&mut sine(440.0), RATE, 9600);
+        let out = render(&mut sine(440.0).chorus(1.5, 0.5, 0.0, 3), RATE, 9600);
+        assert_close(&out.iter().map(|[left, _]| *left).collect::<Vec<_>>(), &dry, 1e-5);
+        assert_close(&out.iter().map(|[_, right]| *right).collect::<Vec<_>>(), &dry, 1e-5);
+    }
+
+    #[test]
+    fn flanger_notches_sweep_over_time() {
+        const RATE: u32 = 48000;
+        // A 4 second sweep from a 5.5ms delay, up to 10ms at 1s and down to 1ms at 3s.
+        let dry = render(&mut noise_seeded(7), RATE, 4 * RATE as usize);
+        let wet = render(&mut noise_seeded(7).flanger(0.25, 1.0, 0.0, 0.5), RATE, 4 * RATE as usize);
+        // How much of the noise at hz survives in a 200ms window around secs.
+        let response = |secs: f32, hz: f32| {
+            let window = (secs * RATE as f32) as usize - 4800..(secs * RATE as f32) as usize + 4800;
+            magnitude_at(&wet[window.clone()], RATE, hz) / magnitude_at(&dry[window], RATE, hz)
+        };
+        // Where the delay is half a cycle the comb has a notch, and a whole cycle a peak.
+        assert!(response(1.0, 50.0) < 0.3, "{}", response(1.0, 50.0));
+        assert!(response(1.0, 500.0) > 0.7, "{}", response(1.0, 500.0));
+        assert!(response(3.0, 500.0) < 0.3, "{}", response(3.0, 500.0));
+        assert!(response(3.0, 50.0) > 0.7, "{}", response(3.0, 50.0));
+
+        let out = render(&mut noise_seeded(7).flanger(0.25, 1.0, 0.9, 0.0), RATE, RATE as usize);
+        assert_eq!(out, dry[..RATE as usize]);
+
+        // Feedback past 1 is clamped, so it rings but never blows up.
+        let out = render(&mut noise_seeded(7).flanger(0.25, 1.0, 5.0, 1.0), RATE, 4 * RATE as usize);
+        assert!(out.iter().all(|s| s.abs() < 100.0));
+    }
+
+    #[test]
+    fn follower_rises_with_a_burst_then_decays() {
+        let burst = || envelope(vec![(0.1, 0.0), (0.1, 1.0), (0.3, 1.0), (0.3, 0.0)]);
+        for rate in [1000, 48000] {
+            let at = |secs: f32| (secs * rate as f32) as usize;
+            let out = render(&mut burst().envelope_follower(0.01, 0.05), rate, at(0.6));
+            assert!(silent(&out[..at(0.1)]));
+            // One time constant in, either way.
+            assert!((out[at(0.11) - 1] - (1.0 - (-1f32).exp())).abs() < 0.01, "{rate}: {}", out[at(0.11) - 1]);
+            assert!(out[at(0.3) - 1] > 0.99);
+            assert!((out[at(0.35) - 1] - (-1f32).exp()).abs() < 0.01, "{rate}: {}", out[at(0.35) - 1]);
+            assert!(out[at(0.3)..].windows(2).all(|pair| pair[1] < pair[0]));
+        }
+
+        // Audio rather than a level is followed by its magnitude, not its average.
+        let out = render(&mut (sine(50.0).wrap() * burst()).envelope_follower(0.001, 0.05), 1000, 600);
+        assert!(settled_peak(&out[..300]) > 0.9 && out[250] > 0.5);
+    }
+
+    #[test]
+    fn ping_pong_echoes_alternate_sides() {
+        let out = render(&mut impulse().ping_pong(0.1, 0.5, 1.0), RATE, 500);
+        let left: Vec<f32> = out.iter().map(|[left, _]| *left).collect();
+        let right: Vec<f32> = out.iter().map(|[_, right]| *right).collect();
+        assert_eq!(nonzero(&left), [100, 300]);
+        assert_eq!(nonzero(&right), [200, 400]);
+        assert_close(&[left[100], right[200], left[300], right[400]], &[1.0, 0.5, 0.25, 0.125], 1e-6);
+
+        // Too much feedback is limited, so echoes still die away.
+        let out = render(&mut impulse().ping_pong(0.01, 10.0, 1.0), RATE, 10000);
+        assert!(out[9000..].iter().all(|frame| frame.iter().all(|s| s.abs() < 0.01)));
+    }
+
+    #[test]
+    fn gate_opens_and_closes_with_hysteresis() {
+        // Under the threshold, over it, between it and half of it, under both, then between
+        // again, as steps in level.
+        let level = envelope(vec![
+            (0.0, 0.1), (0.2, 0.1),
+            (0.2, 1.0), (0.5, 1.0),
+            (0.5, 0.4), (0.8, 0.4),
+            (0.8, 0.1), (1.0, 0.1),
+            (1.0, 0.4),
+        ]);
+        let mut gate = level.noise_gate(0.5, 0.001, 0.001);
+        let open: Vec<bool> = (0..1200)
+            .map(|_| {
+                gate.update(SampleTime { count: 1, rate: RATE });
+                gate.open
+            })
+            .collect();
+        let changes: Vec<usize> = (1..open.len()).filter(|&i| open[i] != open[i - 1]).collect();
+        // The level follower reaches the threshold within a sample of the rise, and takes
+        // 14 of its 20ms release to fall from 0.4 under half the threshold.
+        assert_eq!(changes, [200, 813]);
+        assert!(gate.sample() == 0.0);
+    }
+
+    #[test]
+    fn repeat_loops_a_ramp_without_gaps() {
+        let ramp: Vec<f32> = (0..5).map(|i| i as f32 / 5.0).collect();
+        let mut looped = repeat_n(2, || play_sample(ramp.clone(), RATE));
+        assert_eq!(looped.length().map(|length| length.count), Some(10));
+        let out = render(&mut looped, RATE, 12);
+        assert_eq!(out[..10], [ramp.clone(), ramp.clone()].concat());
+        assert!(looped.finished() && silent(&out[10..]));
+
+        let out = render(&mut repeat(|| play_sample(ramp.clone(), RATE)), RATE, 15);
+        assert_eq!(out, ramp.repeat(3));
+    }
+
+    #[test]
+    fn advancing_matches_rendering_and_discarding() {
+        let graph = || (sine(220.0).wrap() * adsr(0.0..0.3, 0.05, 0.1, 0.5, 0.2)).echo(0.1, 0.5).reverb(0.5, 0.5, 0.3);
+        // Part way through the release and the echoes of it.
+        let full = render(&mut graph(), RATE, 1000);
+        let mut seeked = graph();
+        seeked.advance(SampleTime { count: 400, rate: RATE });
+        assert_eq!(render(&mut seeked, RATE, 600), full[400..]);
+        assert!(!silent(&full[400..]));
+    }
+
+    #[test]
+    fn reset_graphs_replay_identically() {
+        let graph = || {
+            (saw(110.0).wrap() * adsr(0.0..0.3, 0.05, 0.1, 0.5, 0.2) + noise().wrap() * 0.1)
+                .flanger(2.0, 0.5, 0.5, 0.5)
+                .noise_gate(0.05, 0.01, 0.05)
+                .echo(0.1, 0.5)
+                .reverb(0.5, 0.5, 0.3)
+                .ping_pong(0.05, 0.3, 0.5)
+        };
+        let mut played = graph();
+        let first = render(&mut played, RATE, 1000);
+        played.reset();
+        assert_eq!(render(&mut played, RATE, 1000), first);
+        assert_eq!(render(&mut graph(), RATE, 1000), first);
+    }
+
+    #[test]
+    fn add_of_envelopes_reports_the_later_end() {
+        // Released at 0.5s and 1s, each ending 0.2s after.
+        let early = || adsr(0.0..0.5, 0.1, 0.1, 0.5, 0.2);
+        let late = || adsr(0.0..1.0, 0.1, 0.1, 0.5, 0.2);
+        let mut sum = early().wrap() + late();
+        assert_eq!(sum.length().map(|length| length.count), Some(1_200_000));
+        assert_eq!((late().wrap() + early()).length().map(|length| length.count), Some(1_200_000));
+        // An oscillator never ends, but gated by an envelope ends with it.
+        assert!((sine(100.0).wrap() + early()).length().is_none());
+        assert_eq!((sine(100.0).wrap() * early()).length().map(|length| length.count), Some(700_000));
+
+        render(&mut sum, RATE, 710);
+        assert!(sum.0.left.finished() && !sum.finished());
+        render(&mut sum, RATE, 500);
+        assert!(sum.finished());
+        let mut gated = sine(100.0).wrap() * early();
+        render(&mut gated, RATE, 710);
+        assert!(gated.finished());
+    }
+
+    #[test]
+    fn builder_attack_reaches_full_level_in_its_time() {
+        let mut envelope = ADSR::builder().active(0.0..10.0).attack(0.1).decay(0.2).sustain(0.5).build().unwrap();
+        let out = render(&mut envelope, RATE, 101);
+        assert!((out[49] - 0.5).abs() < 0.011, "{}", out[49]);
+        assert!(out[..99].iter().all(|&level| level < 1.0));
+        // Rounding can leave it a hair under until the next sample.
+        assert!((out[99] - 1.0).abs() < 1e-5, "{}", out[99]);
+        assert_eq!(out[100], 1.0);
+
+        assert!(matches!(ADSR::builder().sustain(1.5).build(), Err(AdsrError::InvalidSustain(_))));
+        assert!(matches!(ADSR::builder().sustain(-0.1).build(), Err(AdsrError::InvalidSustain(_))));
+        assert!(matches!(ADSR::builder().release(-1.0).build(), Err(AdsrError::InvalidTime(_))));
+    }
+
+    #[test]
+    fn decay_to_sustain_takes_its_time() {
+        let mut envelope = adsr(0.0..10.0, 0.0, 0.5, 0.6, 0.1);
+        let out = render(&mut envelope, 48000, 48000);
+        // Full level after the instant attack, then 24000 samples of decay, give or take
+        // rounding in the f32 progress.
+        assert_eq!(out[0], 1.0);
+        let sustained = out.iter().position(|&level| level == 0.6).unwrap();
+        assert!((23995..=24005).contains(&sustained), "{sustained}");
+        assert!((out[12000] - 0.8).abs() < 1e-3, "{}", out[12000]);
+
+        // Times and rates agree, for the same shape either way.
+        let from_rates = ADSR::from_rates(0.0..10.0, f32::INFINITY, 0.4 / 0.5, 0.6, 0.6 / 0.1);
+        assert_eq!(render(&mut { from_rates }, 48000, 48000), out);
+        assert_eq!(Patch::default().decay_secs, 0.4 / 15.0);
+    }
+
+    #[test]
+    fn haas_delays_the_lagging_side() {
+        const RATE: u32 = 48000;
+        let dry = render(&mut noise_seeded(3), RATE, 2000);
+        // 10ms is 480 samples.
+        let out = render(&mut noise_seeded(3).haas(0.01, Side::Right), RATE, 2000);
+        let left: Vec<f32> = out.iter().map(|[left, _]| *left).collect();
+        let right: Vec<f32> = out.iter().map(|[_, right]| *right).collect();
+        assert_eq!(right, dry);
+        assert!(silent(&left[..480]) && left[480..] == dry[..1520]);
+
+        let out = render(&mut noise_seeded(3).haas(0.01, Side::Left), RATE, 2000);
+        assert!(out.iter().zip(&dry).all(|([left, _], dry)| left == dry));
+        // Clamped to the 30ms that still reads as width rather than an echo.
+        let out = render(&mut impulse().haas(0.5, Side::Left), RATE, 2000);
+        assert_eq!(nonzero(&out.iter().map(|[_, right]| *right).collect::<Vec<_>>()), [1440]);
+    }
+
+    #[test]
+    fn equalizer_peak_boosts_its_band() {
+        const RATE: u32 = 48000;
+        // 100Hz to 10100Hz over 2s.
+        let sweep = || sine(envelope(vec![(0.0, 100.0), (2.0, 10100.0)]));
+        let out = render(&mut sweep().equalizer().peak(1000.0, 6.0, 1.0), RATE, 2 * RATE as usize);
+        let gain_at = |hz: f32| {
+            let secs = (hz - 100.0) / 5000.0;
+            let window = ((secs - 0.005) * RATE as f32) as usize..((secs + 0.005) * RATE as f32) as usize;
+            out[window].iter().fold(0f32, |peak, s| peak.max(s.abs()))
+        };
+        // 6dB is double.
+        assert!((gain_at(1000.0) - 2.0).abs() < 0.05, "{}", gain_at(1000.0));
+        assert!(gain_at(500.0) > 1.1 && gain_at(500.0) < gain_at(1000.0));
+        assert!((gain_at(8000.0) - 1.0).abs() < 0.1, "{}", gain_at(8000.0));
+
+        // No bands is transparent, and even extreme boosts stay stable.
+        assert_eq!(render(&mut sweep().equalizer(), RATE, 4800), render(&mut sweep(), RATE, 4800));
+        let out = render(&mut sweep().equalizer().peak(1000.0, 100.0, 20.0).low_shelf(100.0, -100.0, 0.1), RATE, RATE as usize);
+        assert!(out.iter().all(|s| s.is_finite() && s.abs() < 1000.0));
+    }
+
+    #[test]
+    fn oversampling_reduces_clipping_aliases() {
+        const RATE: u32 = 48000;
+        let clip = |x: f32| (x * 4.0).clamp(-1.0, 1.0);
+        // Odd harmonics 5 to 13 of 5100Hz fold back to these, none of which are harmonics.
+        let aliases = [22500.0, 12300.0, 2100.0, 8100.0, 18300.0];
+        let aliasing = |samples: &[f32]| -> f32 { aliases.iter().map(|&hz| magnitude_at(samples, RATE, hz)).sum() };
+        let naive = render(&mut sine(5100.0).map(clip), RATE, 4800);
+        let oversampled = render(&mut sine(5100.0).oversample(4, clip), RATE, 4800);
+        assert!(aliasing(&oversampled) < aliasing(&naive) * 0.1, "{} vs {}", aliasing(&oversampled), aliasing(&naive));
+        let fundamental = magnitude_at(&oversampled, RATE, 5100.0) / magnitude_at(&naive, RATE, 5100.0);
+        assert!((0.8..1.2).contains(&fundamental), "{fundamental}");
+    }
+
+    #[test]
+    fn clock_renders_exactly_the_duration() {
+        let duration = std::time::Duration::from_secs(5);
+        for rate in [44100, 48000] {
+            let mut clock = FrameClock::new(rate);
+            let (mut frames, mut blocks) = (0, Vec::new());
+            render_blocks(&mut sine(440.0), &mut clock, 2, 512, duration, |block| {
+                frames += block.len() / 2;
+                blocks.push(block.len() / 2);
+            });
+            assert_eq!(frames, 5 * rate as usize);
+            assert_eq!(clock.elapsed(), duration);
+            assert_eq!(clock.remaining(duration), 0);
+            assert!(blocks[..blocks.len() - 1].iter().all(|&block| block == 512));
+            assert_eq!(*blocks.last().unwrap(), 5 * rate as usize % 512);
+        }
+    }
+
+    #[test]
+    fn normalize_scales_the_peak_to_the_target() {
+        let mut buffer = vec![0.1, -0.25, 0.05, 0.2];
+        assert_eq!(normalize(&mut buffer, 1.0), 4.0);
+        assert_eq!(buffer, [0.4, -1.0, 0.2, 0.8]);
+
+        let mut silence = vec![0.0; 4];
+        assert_eq!(normalize(&mut silence, 1.0), 1.0);
+        assert_eq!(normalize_rms(&mut silence, 1.0), 1.0);
+        assert_eq!(silence, [0.0; 4]);
+        assert_eq!(normalize(&mut [], 1.0), 1.0);
+
+        let mut buffer = vec![0.5, -0.5, 0.5, -0.5];
+        assert_eq!(normalize_rms(&mut buffer, 0.25), 0.5);
+        assert_eq!(buffer, [0.25, -0.25, 0.25, -0.25]);
+    }
+
+    #[test]
+    fn stereo_wav_interleaves_left_then_right() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, stereo(sine(100.0), saw(300.0)), 8000, std::time::Duration::from_millis(100)).unwrap();
+        assert_eq!(u16_at(&wav, 22), 2);
+        assert_eq!(u32_at(&wav, 28), 8000 * 4);
+        assert_eq!(u16_at(&wav, 32), 4);
+        assert_eq!(u32_at(&wav, 40), 800 * 4);
+
+        let frames: Vec<[i16; 2]> = wav[44..]
+            .chunks(4)
+            .map(|frame| [i16::from_le_bytes([frame[0], frame[1]]), i16::from_le_bytes([frame[2], frame[3]])])
+            .collect();
+        assert_eq!(frames.len(), 800);
+        let expected = render(&mut stereo(sine(100.0), saw(300.0)), 8000, 800);
+        for (frame, expected) in frames.iter().zip(&expected) {
+            assert_eq!(*frame, expected.map(|value| (value * i16::MAX as f32) as i16));
+        }
+
+        // The channel count comes from the frame type.
+        let mut wav = Vec::new();
+        write_wav(&mut wav, [sine(100.0), sine(200.0), sine(300.0)], 8000, std::time::Duration::from_millis(100)).unwrap();
+        assert_eq!(u16_at(&wav, 22), 3);
+        assert_eq!(wav.len(), 44 + 800 * 6);
+    }
+
+    #[test]
+    fn ramp_passes_its_midpoint_and_holds_the_end() {
+        let out = render(&mut ramp(0.0, 1.0, 1.0), RATE, 1500);
+        assert_eq!(out[0], 0.0);
+        assert!((out[500] - 0.5).abs() < 1e-6, "{}", out[500]);
+        assert!(out[1000..].iter().all(|&value| value == 1.0));
+
+        // Segment boundaries land exactly on their values.
+        let out = render(&mut envelope(vec![(0.0, 0.0), (0.25, 1.0), (0.5, -0.5), (1.0, 0.25)]), RATE, 1001);
+        assert_eq!([out[0], out[250], out[500], out[1000]], [0.0, 1.0, -0.5, 0.25]);
+    }
+
+    #[test]
+    fn sample_hold_changes_exactly_at_interval_boundaries() {
+        // 0.1s is 4410 samples at 44.1kHz.
+        let out = render(&mut sample_hold(0.1).seeded(5), 44100, 44100);
+        let changes: Vec<usize> = (1..out.len()).filter(|&i| out[i] != out[i - 1]).collect();
+        assert_eq!(changes, (1..10).map(|step| step * 4410).collect::<Vec<_>>());
+        assert!(out.iter().all(|value| (-1.0..1.0).contains(value)));
+        assert_eq!(render(&mut sample_hold(0.1).seeded(5), 44100, 44100), out);
+        assert_ne!(render(&mut sample_hold(0.1).seeded(6), 44100, 44100), out);
+
+        for interval in [0.0, -1.0, f32::NAN] {
+            let out = render(&mut sample_hold(interval), RATE, 10);
+            assert!(out.windows(2).all(|pair| pair[0] != pair[1]), "{interval}: {out:?}");
+        }
+    }
+
+    #[test]
+    fn quantize_snaps_to_the_closer_scale_note() {
+        let scale = Scale::new(Key::C.note(4), Mode::Major);
+        let c = Key::C.note(4);
+        let above_c = |semitones: f32| c.hz() * 2f32.powf(semitones / 12.0);
+        // C sharp isn't in the scale, so between C and D the boundary is a semitone up.
+        let mut quantized = above_c(0.9).quantize(scale);
+        quantized.update(SampleTime { count: 1, rate: RATE });
+        assert!((quantized.sample() - c.hz()).abs() < 1e-3);
+        let mut quantized = above_c(1.1).quantize(scale);
+        quantized.update(SampleTime { count: 1, rate: RATE });
+        assert!((quantized.sample() - Key::D.note(4).hz()).abs() < 1e-3);
+
+        // Between E and F, a semitone apart, the boundary is half way.
+        assert_eq!(scale.nearest(above_c(4.4)), Key::E.note(4));
+        assert_eq!(scale.nearest(above_c(4.6)), Key::F.note(4));
+        assert_eq!(scale.nearest(above_c(1.0)), c);
+        assert_eq!(scale.nearest(above_c(-0.4)), c);
+        assert_eq!(scale.nearest(above_c(-0.6)), Key::B.note(3));
+    }
+
+    #[test]
+    fn auto_pan_keeps_constant_power() {
+        for shape in [Waveform::Sine, Waveform::Triangle] {
+            let out = render(&mut 1.0.auto_pan(2.0, 1.0, shape), RATE, 500);
+            let powers: Vec<f32> = out.iter().map(|[left, right]| left * left + right * right).collect();
+            let (lo, hi) = min_max(&powers);
+            assert!(hi - lo < 1e-4, "{shape:?}: {lo}..{hi}");
+            // It does reach both sides over the cycle.
+            let (left_lo, left_hi) = min_max(&out.iter().map(|[left, _]| *left).collect::<Vec<_>>());
+            assert!(left_lo < 0.01 && left_hi > 0.99 * hi.sqrt(), "{shape:?}: {left_lo}..{left_hi}");
+        }
+
+        let out = render(&mut 1.0.auto_pan(2.0, 0.0, Waveform::Sine), RATE, 500);
+        assert!(out.iter().all(|[left, right]| left == right));
+    }
+
+    #[test]
+    fn cc_writes_update_the_smoothed_source() {
+        let controllers = Controllers::new();
+        let mut wheel = controllers.source(Controllers::MOD_WHEEL, 0.0, 10.0, 0.01);
+        assert_eq!(render(&mut wheel, RATE, 10), [0.0; 10]);
+
+        let writer = controllers.clone();
+        std::thread::spawn(move || assert!(writer.send_bytes(&[0xb3, Controllers::MOD_WHEEL, 127])))
+            .join()
+            .unwrap();
+        let out = render(&mut wheel, RATE, 100);
+        // Glides rather than jumps, reaching 63% in the 10ms smoothing time.
+        assert!(out.windows(2).all(|pair| pair[1] > pair[0]));
+        assert!(out[0] < 2.0);
+        assert!((out[9] - 10.0 * (1.0 - (-1f32).exp())).abs() < 0.1, "{}", out[9]);
+        assert!((out[99] - 10.0).abs() < 1e-3);
+
+        // Other controllers and messages are left alone.
+        assert_eq!(controllers.get(Controllers::EXPRESSION), 0);
+        assert!(!controllers.send_bytes(&[0x90, 60, 100]));
+    }
+
+    #[test]
+    fn fm_pair_partials_follow_bessel_functions() {
+        const RATE: u32 = 8000;
+        // J_0 to J_4 of an index of 1.
+        const J: [f32; 5] = [0.7652, 0.4401, 0.1149, 0.0196, 0.0025];
+        let out = render(&mut fm_pair(100.0, 1.0, 1.0), RATE, RATE as usize);
+        // At 1:1 the lower sidebands fold back over the upper ones, so harmonic n gets
+        // J_(n-1) from above and, with alternating sign, J_(n+1) from below.
+        for (n, expected) in [(1, J[0] - J[2]), (2, J[1] + J[3]), (3, J[2] - J[4])] {
+            let partial = magnitude_at(&out, RATE, 100.0 * n as f32);
+            assert!((partial - expected).abs() < 0.01, "{n}: {partial} vs {expected}");
+        }
+        assert!(magnitude_at(&out, RATE, 150.0) < 1e-3);
+
+        // With no modulation it's exactly Sine, and a phase modulated one lines up the same way.
+        let plain = render(&mut fm_operator(100.0, 2.0), RATE, 100);
+        assert_close(&plain, &render(&mut sine(200.0), RATE, 100), 1e-6);
+        let modulated = render(&mut fm_operator(100.0, 1.0).modulated_by(sine(30.0).wrap() * 0.5), RATE, 100);
+        assert_close(&modulated, &render(&mut sine(100.0).phase_modulate(sine(30.0), 0.5), RATE, 100), 1e-6);
+    }
+
+    #[test]
+    fn phase_modulation_index_ignores_modulator_frequency() {
+        const RATE: u32 = 8000;
+        // J_1 of indexes 1 and 0.5.
+        let (j1_of_1, j1_of_half) = (0.4401, 0.2423);
+        for modulator_hz in [50.0, 100.0] {
+            // 1 radian of phase modulation is an index of 1 whatever the modulator's frequency.
+            let pm = render(&mut sine(1000.0).phase_modulate(sine(modulator_hz), 1.0), RATE, RATE as usize);
+            let sideband = magnitude_at(&pm, RATE, 1000.0 + modulator_hz);
+            assert!((sideband - j1_of_1).abs() < 0.01, "{modulator_hz}: {sideband}");
+
+            // The same modulator as 50Hz of vibrato is an index of deviation / modulator hz,
+            // so doubling the modulator's frequency halves it.
+            let fm = render(&mut sine(sine(modulator_hz).wrap() * 50.0 + 1000.0), RATE, RATE as usize);
+            let sideband = magnitude_at(&fm, RATE, 1000.0 + modulator_hz);
+            let expected = if modulator_hz == 50.0 { j1_of_1 } else { j1_of_half };
+            assert!((sideband - expected).abs() < 0.01, "{modulator_hz}: {sideband}");
+        }
+    }
+
+    #[test]
+    fn multi_tap_impulse_appears_at_each_tap() {
+        let out = render(&mut impulse().multi_tap(vec![(0.25, 0.5), (0.1, -0.75)]), RATE, 500);
+        // The dry impulse, then each tap, in time order whatever order they were given in.
+        assert_eq!(nonzero(&out), [0, 100, 250]);
+        assert_eq!([out[0], out[100], out[250]], [1.0, -0.75, 0.5]);
+
+        // Taps at the same time sum.
+        let out = render(&mut impulse().multi_tap(vec![(0.1, 0.25), (0.1, 0.5)]), RATE, 500);
+        assert_eq!(nonzero(&out), [0, 100]);
+        assert_eq!(out[100], 0.75);
+    }
+
+    #[test]
+    fn convolution_matches_direct_convolution() {
+        let direct = |input: &[f32], ir: &[f32]| -> Vec<f32> {
+            (0..input.len())
+                .map(|n| ir.iter().enumerate().filter(|&(k, _)| k <= n).map(|(k, h)| h * input[n - k]).sum())
+                .collect()
+        };
+        let input = render(&mut noise_seeded(9), RATE, 1000);
+        let ir = [0.5, -0.25];
+        let out = render(&mut noise_seeded(9).convolve(ir.to_vec()), RATE, 1000);
+        assert_close(&out, &direct(&input, &ir), 1e-6);
+
+        // Past the first block, the tail goes through the partitioned FFT.
+        let ir: Vec<f32> = render(&mut noise_seeded(4), RATE, 300).iter().enumerate().map(|(i, s)| s * (-(i as f32) / 50.0).exp()).collect();
+        let out = render(&mut noise_seeded(9).convolve(ir.clone()), RATE, 1000);
+        assert_close(&out, &direct(&input, &ir), 1e-4);
+
+        assert_eq!(render(&mut noise_seeded(9).convolve(vec![1.0]), RATE, 1000), input);
+    }
+
+    #[test]
+    fn divisions_convert_from_tempo() {
+        let quarter = Division::note(4);
+        assert_eq!(quarter.secs(120.0), 0.5);
+        assert_eq!(quarter.samples(120.0, 48000), 24000);
+        assert_eq!(Division::note(8).dotted().secs(120.0), 0.375);
+        assert!((Division::note(8).triplet().secs(120.0) - 1.0 / 6.0).abs() < 1e-6);
+        assert_eq!(Division::note(8).triplet().samples(120.0, 48000), 8000);
+
+        for bpm in [0.0, -120.0, f32::NAN] {
+            assert_eq!(quarter.secs(bpm), 60.0, "{bpm}");
+        }
+        // Echoes are a quarter note apart.
+        let out = render(&mut impulse().synced_echo(quarter, 120.0, 0.5), RATE, 1500);
+        assert_eq!(nonzero(&out), [0, 500, 1000]);
+    }
+
+    #[test]
+    fn to_mono_averages_channels() {
+        let hard_left = stereo(sine(100.0), 0.0);
+        let expected: Vec<f32> = render(&mut sine(100.0), RATE, 100).iter().map(|s| s / 2.0).collect();
+        assert_close(&render(&mut hard_left.to_mono(), RATE, 100), &expected, 1e-7);
+
+        let out = render(&mut stereo(sine(100.0), saw(30.0)).to_mono().to_stereo(), RATE, 100);
+        assert!(out.iter().all(|[left, right]| left == right));
+        assert_eq!(render(&mut 0.5.to_stereo(), RATE, 1), [[0.5, 0.5]]);
+    }
+
+    #[test]
+    fn clip_report_counts_samples_over_unity() {
+        let samples = vec![0.5, -1.5, 1.0, 2.0, -1.0001, 0.0];
+        let (buffer, report) = render_checked(play_sample(samples.clone(), RATE), RATE, 6);
+        assert_eq!(buffer, samples);
+        // Exactly 1 doesn't clip.
+        assert_eq!(report, ClipReport { peak: 2.0, clipped: 3 });
+        assert!(report.is_clipping());
+
+        let (_, report) = render_checked(sine(100.0).wrap() * 0.5, RATE, 1000);
+        assert!(!report.is_clipping() && report.peak <= 0.5);
+    }
+
+    #[test]
+    fn smoothed_converges_monotonically_to_a_step() {
+        for rate in [1000, 48000] {
+            let at = |secs: f32| (secs * rate as f32) as usize;
+            let step = envelope(vec![(0.0, 0.0), (0.1, 0.0), (0.1, 1.0)]);
+            let out = render(&mut step.smoothed(0.01), rate, at(0.2));
+            assert!(silent(&out[..at(0.1)]));
+            assert!(out.windows(2).all(|pair| pair[1] >= pair[0] && pair[1] <= 1.0));
+            // One time constant after the step, whatever the rate.
+            assert!((out[at(0.11) - 1] - (1.0 - (-1f32).exp())).abs() < 0.01, "{rate}: {}", out[at(0.11) - 1]);
+            assert!(1.0 - out[at(0.2) - 1] < 1e-3);
+        }
+    }
+
+    #[test]
+    fn pitch_shift_up_an_octave_doubles_the_frequency() {
+        const RATE: u32 = 8000;
+        let window_hz = 1.0 / PitchShift::<f32>::WINDOW_SECS;
+        let dominant = |hz: f32| {
+            let out = render(&mut sine(hz).pitch_shift(12.0), RATE, RATE as usize);
+            (10..=100)
+                .map(|tenths| tenths as f32 * 10.0)
+                .max_by(|&a, &b| magnitude_at(&out, RATE, a).total_cmp(&magnitude_at(&out, RATE, b)))
+                .unwrap()
+        };
+        // The heads are half a window apart, which is a whole number of cycles at 200Hz, so
+        // they add up in phase.
+        assert_eq!(dominant(200.0), 400.0);
+        // At 220Hz they're half a cycle apart, and the shifted pitch splits into partials a
+        // window rate either side.
+        assert!((dominant(220.0) - 440.0).abs() <= window_hz, "{}", dominant(220.0));
+
+        assert_eq!(render(&mut sine(220.0).pitch_shift(0.0), RATE, 1000), render(&mut sine(220.0), RATE, 1000));
+    }
+
+    #[test]
+    fn granular_emits_grains_at_its_density() {
+        for rate in [1000, 44100] {
+            // A grain every 25ms, each 100ms long, so four overlap.
+            let mut grains = granular(vec![1.0; 1000], 1000, 0.1, 40.0);
+            let out = render(&mut grains, rate, rate as usize / 2);
+            assert_eq!(grains.grains_started(), 20, "{rate}");
+            // Once they overlap fully, the Hann windows sum to a steady level.
+            let (lo, hi) = min_max(&out[rate as usize / 5..]);
+            assert!(hi - lo < 1e-3 && (hi - 1.0).abs() < 1e-3, "{rate}: {lo}..{hi}");
+        }
+
+        let sprayed = || granular(render(&mut noise(), 1000, 1000), 1000, 0.05, 30.0).with_spray(0.2).seeded(3);
+        assert_eq!(render(&mut sprayed(), 1000, 500), render(&mut sprayed(), 1000, 500));
+        assert_ne!(render(&mut sprayed(), 1000, 500), render(&mut sprayed().seeded(4), 1000, 500));
+    }
+
+    #[test]
+    fn random_melody_is_reproducible_and_in_scale() {
+        let scale = Scale::new(Key::D.note(4), Mode::Dorian);
+        let melody = |seed| random_melody(scale, vec![(Division::note(8), 3.0), (Division::note(4), 1.0)], 120.0).seeded(seed);
+        let notes = |mut melody: RandomMelody| {
+            let mut notes = Vec::new();
+            for _ in 0..10_000 {
+                melody.update(SampleTime { count: 1, rate: RATE });
+                if notes.last() != melody.current_note().as_ref() {
+                    notes.extend(melody.current_note());
+                }
+            }
+            notes
+        };
+        let first = notes(melody(7));
+        assert!(first.len() > 10);
+        assert!(first.iter().all(|&note| scale.contains(note)));
+        assert_eq!(notes(melody(7)), first);
+        assert_ne!(notes(melody(8)), first);
+        assert_eq!(render(&mut melody(7), RATE, 2000), render(&mut melody(7), RATE, 2000));
+
+        // Quarters at 120bpm are 500 samples, so at half gate with a quick release each note is
+        // silent well before the next starts.
+        let envelope = adsr_gated(0.001, 0.01, 0.8, 0.01);
+        let mut gated = random_melody(scale, Vec::new(), 120.0).with_envelope(envelope).with_gate(0.5);
+        let out = render(&mut gated, RATE, 2000);
+        for note in 0..4 {
+            assert!(!silent(&out[note * 500 + 10..note * 500 + 250]), "{note}");
+            assert!(silent(&out[note * 500 + 270..note * 500 + 500]), "{note}");
+        }
+    }
+
+    #[test]
+    fn adsr_schedule_is_exact_hours_in() {
+        // Seven hours in, an f32 clock can't tell neighbouring samples apart at this rate.
+        const RATE: u32 = 1024;
+        let mut envelope = adsr(25200.25..25200.75, 0.01, 0.01, 0.5, 0.1);
+        envelope.update(SampleTime { count: 25200 * RATE as u64, rate: RATE });
+        let out = render(&mut envelope, RATE, 1024);
+        let first = out.iter().position(|&x| x > 0.0).unwrap();
+        assert_eq!(first, 256);
+        assert_eq!(out[767], 0.5);
+        assert!(out[768] < 0.5);
+    }
+
+    #[test]
+    fn adsr_gate_length_is_exact_hours_in() {
+        const RATE: u32 = 1024;
+        let mut envelope = adsr_gated(0.0, 0.0, 1.0, 0.25);
+        envelope.update(SampleTime { count: 25200 * RATE as u64, rate: RATE });
+        envelope.gate_on();
+        assert_eq!(envelope.length(), None);
+        render(&mut envelope, RATE, 100);
+        envelope.gate_off();
+        let expected = 25200.0 + 100.0 / RATE as f64 + 0.25;
+        let length = envelope.length().unwrap().as_secs_f64();
+        assert!((length - expected).abs() < 1e-6, "{length} != {expected}");
+        let out = render(&mut envelope, RATE, 512);
+        let end = out.iter().position(|&x| x == 0.0).unwrap();
+        assert_eq!(end, 255);
+        assert!(envelope.finished());
+    }
+
+    #[test]
+    fn mix_bus_sums_buses_into_the_master() {
+        let mut bus = mix_bus();
+        assert!(silent(&render(&mut bus, RATE, 10)));
+
+        bus.add_bus("drums").unwrap();
+        bus.add_bus("keys").unwrap();
+        bus.add_source("drums", 1.0).unwrap();
+        bus.add_source("keys", 0.5).unwrap();
+        bus.set_gain("drums", 0.5).unwrap();
+        bus.set_gain("keys", 2.0).unwrap();
+        assert_eq!(render(&mut bus, RATE, 4), [1.5; 4]);
+
+        bus.set_master_gain(0.5);
+        assert_eq!(render(&mut bus, RATE, 4), [0.75; 4]);
+
+        // Keys now go through the drums' gain on the way to the master.
+        bus.set_master_gain(1.0);
+        bus.route("keys", "drums").unwrap();
+        assert_eq!(render(&mut bus, RATE, 4), [1.0; 4]);
+        bus.route_to_master("keys").unwrap();
+        assert_eq!(render(&mut bus, RATE, 4), [1.5; 4]);
+
+        // An empty bus adds nothing.
+        bus.add_bus("empty").unwrap();
+        assert_eq!(render(&mut bus, RATE, 4), [1.5; 4]);
+    }
+
+    #[test]
+    fn mix_bus_rejects_loops_and_unknown_buses() {
+        let mut bus = mix_bus();
+        bus.add_bus("a").unwrap();
+        bus.add_bus("b").unwrap();
+        bus.add_bus("c").unwrap();
+        assert!(matches!(bus.add_bus("a"), Err(MixBusError::DuplicateBus(_))));
+        assert!(matches!(bus.add_source("d", 1.0), Err(MixBusError::NoSuchBus(_))));
+        assert!(matches!(bus.route("a", "a"), Err(MixBusError::Cycle(..))));
+        bus.route("a", "b").unwrap();
+        bus.route("b", "c").unwrap();
+        assert!(matches!(bus.route("c", "a"), Err(MixBusError::Cycle(..))));
+
+        // The chain runs deepest first, so a's source reaches the master the same sample.
+        bus.add_source("a", 1.0).unwrap();
+        assert_eq!(render(&mut bus, RATE, 2), [1.0; 2]);
+    }
+
+    #[test]
+    fn sustain_pedal_defers_note_off_until_released() {
+        let (c, e) = (Key::C.note(4), Key::E.note(4));
+        let mut synth = synth(4, 0.01, 0.01, 0.8, 0.05);
+        synth.note_on(c);
+        synth.note_on(e);
+        render(&mut synth, RATE, 50);
+
+        synth.set_sustain(true);
+        synth.note_off(c);
+        synth.note_off(e);
+        // Well past the release time, both are still sustaining.
+        render(&mut synth, RATE, 500);
+        assert!(synth.voices.iter().all(|voice| voice.envelope().level == 0.8));
+        assert!(!silent(&render(&mut synth, RATE, 10)));
+
+        // Pressing C again while sustained keeps it going after the pedal is let up.
+        synth.note_on(c);
+        synth.set_sustain(false);
+        render(&mut synth, RATE, 100);
+        assert_eq!(voice_notes(&synth).len(), 3);
+        let held: Vec<_> = synth.voices.iter().filter(|voice| !voice.is_finished()).map(|voice| voice.note).collect();
+        assert_eq!(held, [c]);
+
+        synth.note_off(c);
+        render(&mut synth, RATE, 100);
+        assert!(silent(&render(&mut synth, RATE, 10)));
+    }
+}